@@ -0,0 +1,255 @@
+//! Compares the in-memory packet stores when reassembling a file the
+//! size of `AsYouLikeIt.txt` and a much larger one, with the packets
+//! arriving in a random order.
+//!
+//! Besides the timings, this prints how much heap memory each store
+//! uses to hold a whole file.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    hint::black_box,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rust_segmented_file_client::{
+    output::OutputConfig,
+    packet_group::PacketGroup,
+    packet_store::{MemoryStore, PacketStore},
+    packets::{Data, FileNameMode, Header, Packet, PacketRef},
+};
+
+// Counts the bytes currently allocated so we can report memory use.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Keeps each payload in its own allocation in a `HashMap`. This is
+// simple, but `MemoryStore` is faster and uses less memory; it's kept
+// here for comparison.
+#[derive(Debug, Default)]
+struct HashMapStore {
+    packets: HashMap<u16, Vec<u8>>,
+}
+
+impl PacketStore for HashMapStore {
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    fn contains(&self, packet_number: u16) -> bool {
+        self.packets.contains_key(&packet_number)
+    }
+
+    fn get(&self, packet_number: u16) -> io::Result<Option<Cow<'_, [u8]>>> {
+        Ok(self
+            .packets
+            .get(&packet_number)
+            .map(|data| Cow::Borrowed(data.as_slice())))
+    }
+
+    fn insert(&mut self, packet_number: u16, data: &[u8]) -> io::Result<()> {
+        self.packets.insert(packet_number, data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, packet_number: u16) {
+        self.packets.remove(&packet_number);
+    }
+
+    fn packet_numbers(&self) -> Vec<u16> {
+        let mut packet_numbers: Vec<u16> = self.packets.keys().copied().collect();
+        packet_numbers.sort_unstable();
+        packet_numbers
+    }
+
+    fn clear(&mut self) {
+        self.packets = HashMap::new();
+    }
+}
+
+// The most data the classroom server puts in a packet.
+const PAYLOAD_SIZE: usize = 1024;
+
+struct TestFile {
+    name: &'static str,
+    contents: Vec<u8>,
+}
+
+impl TestFile {
+    fn as_you_like_it() -> Self {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/java-server-lib/testFiles/AsYouLikeIt.txt"
+        );
+        Self {
+            name: "AsYouLikeIt.txt",
+            contents: fs::read(path).expect("the test file should exist"),
+        }
+    }
+
+    // The largest file the protocol allows.
+    fn largest() -> Self {
+        Self {
+            name: "64MiB",
+            contents: (0..=u8::MAX).cycle().take(65_536 * PAYLOAD_SIZE).collect(),
+        }
+    }
+
+    // The payloads, keyed by packet number, in a random (but repeatable)
+    // order.
+    fn payloads(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut payloads: Vec<(u16, Vec<u8>)> = (0..=u16::MAX)
+            .zip(self.contents.chunks(PAYLOAD_SIZE))
+            .map(|(packet_number, chunk)| (packet_number, chunk.to_vec()))
+            .collect();
+        payloads.shuffle(&mut StdRng::seed_from_u64(17));
+        payloads
+    }
+
+    // The header followed by the data packets in the same order as
+    // `payloads`, encoded as they'd arrive from the server.
+    fn datagrams(&self) -> Vec<Vec<u8>> {
+        let payloads = self.payloads();
+        let last_packet_number = payloads.iter().map(|&(number, _)| number).max();
+        let mut packets: Vec<Packet> =
+            vec![Header::new(0, self.name).expect("valid header").into()];
+        for (packet_number, payload) in payloads {
+            let data = Data::new(0, packet_number, payload).expect("valid data packet");
+            packets.push(
+                data.with_last_packet(Some(packet_number) == last_packet_number)
+                    .into(),
+            );
+        }
+        packets.iter().map(Packet::encode).collect()
+    }
+}
+
+type NewStore = fn() -> Box<dyn PacketStore>;
+
+const STORES: [(&str, NewStore); 2] = [
+    ("hash-map", || Box::new(HashMapStore::default())),
+    ("chunked", || Box::new(MemoryStore::default())),
+];
+
+// Insert every payload and then read them all back in order, which is
+// what receiving and then writing a file does.
+fn fill_and_drain(mut store: Box<dyn PacketStore>, payloads: &[(u16, Vec<u8>)]) -> usize {
+    let count = payloads.len();
+    for (packet_number, payload) in payloads {
+        store
+            .insert(*packet_number, payload)
+            .expect("in-memory stores can't fail");
+    }
+    (0..=u16::MAX)
+        .take(count)
+        .map(|packet_number| {
+            store
+                .get(packet_number)
+                .ok()
+                .flatten()
+                .map_or(0, |data| data.len())
+        })
+        .sum()
+}
+
+fn report_memory(file: &TestFile) {
+    for (store_name, new_store) in STORES {
+        let payloads = file.payloads();
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let mut store = new_store();
+        for (packet_number, payload) in &payloads {
+            store
+                .insert(*packet_number, payload)
+                .expect("in-memory stores can't fail");
+        }
+        let used = ALLOCATED.load(Ordering::Relaxed).saturating_sub(before);
+        println!(
+            "memory/{store_name}/{}: {used} bytes of heap for {} bytes of payload",
+            file.name,
+            file.contents.len()
+        );
+        drop(store);
+    }
+}
+
+fn stores(c: &mut Criterion) {
+    let mut group = c.benchmark_group("store");
+    for file in [TestFile::as_you_like_it(), TestFile::largest()] {
+        report_memory(&file);
+        group.throughput(Throughput::Bytes(file.contents.len() as u64));
+        let payloads = file.payloads();
+        for (store_name, new_store) in STORES {
+            group.bench_with_input(
+                BenchmarkId::new(store_name, file.name),
+                &payloads,
+                |b, payloads| b.iter(|| black_box(fill_and_drain(new_store(), payloads))),
+            );
+        }
+    }
+    group.finish();
+}
+
+// The whole path through `PacketGroup`: processing every packet and
+// writing the file.
+fn write_file(c: &mut Criterion) {
+    let output_dir = tempfile::tempdir().expect("can create a temporary directory");
+    let config = OutputConfig::new(output_dir.path());
+    let mut group = c.benchmark_group("write_file");
+    group.sample_size(10);
+    for file in [TestFile::as_you_like_it(), TestFile::largest()] {
+        group.throughput(Throughput::Bytes(file.contents.len() as u64));
+        let datagrams = file.datagrams();
+        for (store_name, new_store) in STORES {
+            group.bench_with_input(
+                BenchmarkId::new(store_name, file.name),
+                &datagrams,
+                |b, datagrams| {
+                    b.iter(|| {
+                        let mut packet_group = PacketGroup::default().with_store(new_store());
+                        for datagram in datagrams {
+                            let packet = PacketRef::parse(datagram, FileNameMode::Strict)
+                                .expect("valid packets");
+                            packet_group
+                                .process_packet_ref(packet)
+                                .expect("consistent packets");
+                        }
+                        packet_group
+                            .write_file(0, &config)
+                            .expect("can write the file")
+                    });
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stores, write_file);
+criterion_main!(benches);
@@ -0,0 +1,604 @@
+//! Saving the state of a `FileManager` to disk so that a client that
+//! dies part way through a download can pick up where it left off.
+//!
+//! A checkpoint is a single binary file. All integers are
+//! little-endian.
+//!
+//! ```text
+//! magic              b"SFCCKPT3"
+//! number of files    u16
+//! for each file:
+//!   file ID          u8
+//!   file name        u8 (0 if unknown, 1 if known), then if known
+//!                    u32 length and the name's bytes
+//!   packet count     u32 (0 if we haven't seen the last packet)
+//!   bytes received   u64
+//!   state            u8:
+//!     0 (receiving)  u32 number of packets streamed to disk, then if
+//!                    that isn't 0, u64 length and the bytes we'd
+//!                    streamed; then u32 bitmap length, then a bitmap
+//!                    with bit `n % 8` of byte `n / 8` set if we have
+//!                    packet `n`, then each of those packets in
+//!                    increasing order as a u32 length and the payload
+//!     1 (written)    the file's size (u64) and SHA-256 hash (32 bytes),
+//!                    then u32 length and the bytes of the path we
+//!                    wrote to
+//!     2 (skipped)    the file's size and hash as for 1; the collision
+//!                    policy skipped the file
+//! ```
+//!
+//! Names and paths are saved as their raw bytes on Unix and as their
+//! UTF-16 code units (little-endian) on Windows, so they come back
+//! exactly.
+//!
+//! What we'd streamed to a temporary file (see
+//! `PacketGroup::write_prefix`) is read back and saved, and goes to a
+//! new temporary file the next time we write; the old one is cleaned
+//! up when the client restarts. We don't keep the packets of a file the
+//! collision policy skipped or whose stream failed, so those start
+//! over. The bytes received by a file that's still receiving are
+//! counted from what we restore rather than taken from the checkpoint.
+
+#[cfg(windows)]
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(not(windows))]
+use crate::packets::os_string_from_bytes;
+use crate::{
+    packet_group::{FileDigest, PacketGroup, Stream},
+    packets::MAX_PACKETS,
+};
+
+const MAGIC: &[u8; 8] = b"SFCCKPT3";
+
+const RECEIVING: u8 = 0;
+const WRITTEN: u8 = 1;
+const SKIPPED: u8 = 2;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    /// The file doesn't start with the checkpoint magic bytes.
+    NotACheckpoint,
+    /// The file ended part way through a record.
+    Truncated,
+    /// The file is complete but describes an impossible state.
+    Corrupt(String),
+}
+
+impl Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::NotACheckpoint => write!(f, "not a checkpoint file"),
+            Self::Truncated => write!(f, "the checkpoint file is truncated"),
+            Self::Corrupt(reason) => write!(f, "the checkpoint file is corrupt: {reason}"),
+        }
+    }
+}
+
+impl Error for CheckpointError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Write a checkpoint of `groups` to `path`. We write it to a temporary
+/// file next to `path` first and rename it into place, so a crash while
+/// saving leaves the previous checkpoint intact.
+pub(crate) fn save(groups: &HashMap<u8, PacketGroup>, path: &Path) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let bytes = encode(groups)?;
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+/// Read the checkpoint at `path`, returning `None` if there isn't one.
+pub(crate) fn load(path: &Path) -> Result<Option<HashMap<u8, PacketGroup>>, CheckpointError> {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: usize) {
+    // Everything we store with a u32 length is far smaller than 4 GiB.
+    let value = u32::try_from(value).unwrap_or(u32::MAX);
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    push_u32(bytes, value.len());
+    bytes.extend_from_slice(value);
+}
+
+#[cfg(not(windows))]
+fn os_str_bytes(value: &OsStr) -> Cow<'_, [u8]> {
+    Cow::Borrowed(value.as_encoded_bytes())
+}
+
+#[cfg(windows)]
+fn os_str_bytes(value: &OsStr) -> Cow<'_, [u8]> {
+    Cow::Owned(value.encode_wide().flat_map(u16::to_le_bytes).collect())
+}
+
+#[cfg(not(windows))]
+#[expect(
+    clippy::unnecessary_wraps,
+    reason = "Saved names can't be invalid on Unix, but can be on Windows"
+)]
+fn os_string_from_saved(bytes: &[u8]) -> Option<OsString> {
+    Some(os_string_from_bytes(bytes.to_vec()))
+}
+
+// Fails if there's an odd number of bytes.
+#[cfg(windows)]
+fn os_string_from_saved(bytes: &[u8]) -> Option<OsString> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Some(OsString::from_wide(&wide))
+}
+
+// Fails if a packet can't be read back from its group's store, or what
+// we've streamed can't be read back from its temporary file.
+pub(crate) fn encode(groups: &HashMap<u8, PacketGroup>) -> io::Result<Vec<u8>> {
+    let mut file_ids: Vec<u8> = groups.keys().copied().collect();
+    file_ids.sort_unstable();
+
+    let mut bytes = MAGIC.to_vec();
+    // There are at most 256 file IDs.
+    let number_of_files = u16::try_from(file_ids.len()).unwrap_or(u16::MAX);
+    bytes.extend_from_slice(&number_of_files.to_le_bytes());
+    for file_id in file_ids {
+        let group = &groups[&file_id];
+        bytes.push(file_id);
+        match &group.file_name {
+            Some(file_name) => {
+                bytes.push(1);
+                push_bytes(&mut bytes, &os_str_bytes(file_name));
+            }
+            None => bytes.push(0),
+        }
+        push_u32(&mut bytes, group.expected_number_of_packets.unwrap_or(0));
+        bytes.extend_from_slice(&(group.bytes_received as u64).to_le_bytes());
+
+        // Finished files always have a digest.
+        if let (Some(Stream::Finished(path)), Some(digest)) = (&group.stream, group.digest) {
+            bytes.push(if path.is_some() { WRITTEN } else { SKIPPED });
+            bytes.extend_from_slice(&digest.size.to_le_bytes());
+            bytes.extend_from_slice(&digest.sha256);
+            if let Some(path) = path {
+                push_bytes(&mut bytes, &os_str_bytes(path.as_os_str()));
+            }
+        } else {
+            bytes.push(RECEIVING);
+            let streamed = match &group.stream {
+                Some(Stream::Writing(file)) => Some(Cow::Owned(file.read_back()?)),
+                Some(Stream::Restored(streamed)) => Some(Cow::Borrowed(streamed.as_slice())),
+                _ => None,
+            };
+            match streamed {
+                Some(streamed) => {
+                    push_u32(&mut bytes, group.packets_written);
+                    bytes.extend_from_slice(&(streamed.len() as u64).to_le_bytes());
+                    bytes.extend_from_slice(&streamed);
+                }
+                None => push_u32(&mut bytes, 0),
+            }
+            let packet_numbers = group.packets.packet_numbers();
+            let bitmap_length = packet_numbers.last().map_or(0, |&highest| highest / 8 + 1);
+            let mut bitmap = vec![0; usize::from(bitmap_length)];
+            for &packet_number in &packet_numbers {
+                bitmap[usize::from(packet_number / 8)] |= 1 << (packet_number % 8);
+            }
+            push_bytes(&mut bytes, &bitmap);
+            for packet_number in packet_numbers {
+                let data = group.packets.get(packet_number)?.unwrap_or_default();
+                push_bytes(&mut bytes, &data);
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    const fn take(&mut self, length: usize) -> Result<&'a [u8], CheckpointError> {
+        if length > self.bytes.len() {
+            return Err(CheckpointError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, CheckpointError> {
+        Ok(self.take_array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, CheckpointError> {
+        Ok(u32::from_le_bytes(self.take_array()?) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], CheckpointError> {
+        let length = self.u32()?;
+        self.take(length)
+    }
+}
+
+fn corrupt(file_id: u8, reason: &str) -> CheckpointError {
+    CheckpointError::Corrupt(format!("file {file_id}: {reason}"))
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<HashMap<u8, PacketGroup>, CheckpointError> {
+    let mut reader = Reader { bytes };
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(CheckpointError::NotACheckpoint);
+    }
+
+    let number_of_files = u16::from_le_bytes(reader.take_array()?);
+    let mut groups = HashMap::new();
+    for _ in 0..number_of_files {
+        let file_id = reader.u8()?;
+        let file_name = match reader.u8()? {
+            0 => None,
+            1 => Some(
+                os_string_from_saved(reader.bytes()?)
+                    .ok_or_else(|| corrupt(file_id, "bad file name"))?,
+            ),
+            _ => return Err(corrupt(file_id, "bad file name flag")),
+        };
+        let expected_number_of_packets = match reader.u32()? {
+            0 => None,
+            count if count <= MAX_PACKETS => Some(count),
+            _ => return Err(corrupt(file_id, "too many packets")),
+        };
+        let bytes_received = usize::try_from(u64::from_le_bytes(reader.take_array()?))
+            .map_err(|_| corrupt(file_id, "too many bytes"))?;
+        let group = PacketGroup {
+            file_name,
+            expected_number_of_packets,
+            bytes_received,
+            ..PacketGroup::default()
+        };
+        let group = match reader.u8()? {
+            RECEIVING => read_packets(&mut reader, file_id, group)?,
+            state @ (WRITTEN | SKIPPED) => {
+                let Some(expected_number_of_packets) = expected_number_of_packets else {
+                    return Err(corrupt(file_id, "finished without a last packet"));
+                };
+                let digest = FileDigest {
+                    size: u64::from_le_bytes(reader.take_array()?),
+                    sha256: reader.take_array()?,
+                };
+                let path = if state == WRITTEN {
+                    let path = os_string_from_saved(reader.bytes()?)
+                        .ok_or_else(|| corrupt(file_id, "bad path"))?;
+                    Some(PathBuf::from(path))
+                } else {
+                    None
+                };
+                PacketGroup {
+                    packets_written: expected_number_of_packets,
+                    stream: Some(Stream::Finished(path)),
+                    digest: Some(digest),
+                    ..group
+                }
+            }
+            _ => return Err(corrupt(file_id, "bad state")),
+        };
+
+        if groups.insert(file_id, group).is_some() {
+            return Err(corrupt(file_id, "saved twice"));
+        }
+    }
+    if !reader.bytes.is_empty() {
+        return Err(CheckpointError::Corrupt(
+            "unexpected data after the last file".to_string(),
+        ));
+    }
+    Ok(groups)
+}
+
+fn read_packets(
+    reader: &mut Reader<'_>,
+    file_id: u8,
+    mut group: PacketGroup,
+) -> Result<PacketGroup, CheckpointError> {
+    group.bytes_received = 0;
+    let packets_written = reader.u32()?;
+    if packets_written > 0 {
+        if packets_written > group.expected_number_of_packets.unwrap_or(MAX_PACKETS) {
+            return Err(corrupt(file_id, "streamed too many packets"));
+        }
+        let length = usize::try_from(u64::from_le_bytes(reader.take_array()?))
+            .map_err(|_| corrupt(file_id, "streamed too many bytes"))?;
+        let streamed = reader.take(length)?.to_vec();
+        group.prefix_digest.update(&streamed);
+        group.bytes_received = streamed.len();
+        group.packets_written = packets_written;
+        group.stream = Some(Stream::Restored(streamed));
+    }
+
+    let bitmap = reader.bytes()?;
+    if bitmap.len() > MAX_PACKETS / 8 {
+        return Err(corrupt(file_id, "bitmap is too long"));
+    }
+    // The bitmap is at most 8192 bytes long, so every bit's index fits
+    // in a `u16`.
+    let packet_numbers = (0..=u16::MAX)
+        .take(bitmap.len() * 8)
+        .filter(|&packet_number| {
+            bitmap[usize::from(packet_number / 8)] & (1 << (packet_number % 8)) != 0
+        });
+    for packet_number in packet_numbers {
+        if group
+            .expected_number_of_packets
+            .is_some_and(|expected| usize::from(packet_number) >= expected)
+        {
+            return Err(corrupt(file_id, "packet after the last packet"));
+        }
+        if usize::from(packet_number) < group.packets_written {
+            return Err(corrupt(file_id, "packet we'd already streamed"));
+        }
+        let data = reader.bytes()?;
+        group.bytes_received += data.len();
+        group.packets.insert(packet_number, data)?;
+    }
+    Ok(group)
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod checkpoint_tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use crate::{
+        output::OutputConfig,
+        packet_group::{PacketGroup, Stream},
+        packets::{Data, Packet},
+        test_helpers::header,
+    };
+
+    use super::{decode, encode, load, save, CheckpointError};
+
+    fn add(groups: &mut HashMap<u8, PacketGroup>, packet: Packet) {
+        groups
+            .entry(packet.file_id())
+            .or_default()
+            .process_packet(packet)
+            .unwrap();
+    }
+
+    // Packet n is n bytes long.
+    fn data(file_id: u8, packet_number: u16, is_last_packet: bool) -> Packet {
+        let contents = vec![file_id; usize::from(packet_number)];
+        crate::test_helpers::data(file_id, packet_number, is_last_packet, &contents)
+    }
+
+    fn sample() -> HashMap<u8, PacketGroup> {
+        let mut groups = HashMap::new();
+        add(&mut groups, header(1, "partial.txt"));
+        add(&mut groups, data(1, 0, false));
+        add(&mut groups, data(1, 9, false));
+        add(&mut groups, data(1, 20, true));
+        add(&mut groups, data(2, 3, false));
+        groups
+    }
+
+    fn assert_same_state(before: &HashMap<u8, PacketGroup>, after: &HashMap<u8, PacketGroup>) {
+        assert_eq!(before.len(), after.len());
+        for (file_id, group) in before {
+            let restored = &after[file_id];
+            assert_eq!(group.file_name, restored.file_name);
+            assert_eq!(
+                group.expected_number_of_packets,
+                restored.expected_number_of_packets
+            );
+            assert_eq!(*group.packets, *restored.packets);
+            assert_eq!(group.gap_report(), restored.gap_report());
+            assert_eq!(group.progress(), restored.progress());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let groups = sample();
+        assert_same_state(&groups, &decode(&encode(&groups).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn finished_files_stay_finished() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let mut groups = HashMap::new();
+        add(&mut groups, header(5, "done.txt"));
+        add(&mut groups, data(5, 1, true));
+        add(&mut groups, data(5, 0, false));
+        let path = groups
+            .get_mut(&5)
+            .unwrap()
+            .finish(5, &OutputConfig::new(output_dir.path()))
+            .unwrap();
+
+        let restored = decode(&encode(&groups).unwrap()).unwrap();
+        let group = &restored[&5];
+        assert!(group.is_complete());
+        assert!(group.packets.is_empty());
+        assert_eq!(group.stream, Some(Stream::Finished(path)));
+        assert_eq!(group.digest().unwrap(), groups[&5].digest().unwrap());
+    }
+
+    #[test]
+    fn streamed_packets_are_restored() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(output_dir.path());
+        let mut groups = HashMap::new();
+        add(&mut groups, header(5, "streamed.txt"));
+        for packet in [data(5, 1, false), data(5, 2, false), data(5, 4, true)] {
+            add(&mut groups, packet);
+        }
+        add(&mut groups, data(5, 0, false));
+        let group = groups.get_mut(&5).unwrap();
+        assert_eq!(group.write_prefix(5, &config).unwrap(), 3);
+        assert_eq!(group.bytes_received, 7);
+
+        let mut restored = decode(&encode(&groups).unwrap()).unwrap();
+        drop(groups);
+        let group = &restored[&5];
+        assert_eq!(group.packets_written, 3);
+        assert_eq!(group.bytes_received, 7);
+        assert_eq!(group.missing_packets(), vec![3]);
+
+        add(&mut restored, data(5, 3, false));
+        let group = restored.get_mut(&5).unwrap();
+        let path = group.write_file(5, &config).unwrap().unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), vec![5; 10]);
+        assert_eq!(group.digest().unwrap().size, 10);
+        assert_eq!(std::fs::read_dir(output_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn restored_streams_round_trip() {
+        let output_dir = tempfile::tempdir().unwrap();
+        let mut groups = HashMap::new();
+        add(&mut groups, header(5, "streamed.txt"));
+        add(&mut groups, data(5, 0, false));
+        add(&mut groups, data(5, 1, false));
+        groups
+            .get_mut(&5)
+            .unwrap()
+            .write_prefix(5, &OutputConfig::new(output_dir.path()))
+            .unwrap();
+
+        let restored = decode(&encode(&groups).unwrap()).unwrap();
+        let again = decode(&encode(&restored).unwrap()).unwrap();
+        assert_eq!(again[&5].stream, Some(Stream::Restored(vec![5])));
+        assert_eq!(again[&5].packets_written, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_are_kept_exactly() {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+        let mut groups = sample();
+        let file_name = OsString::from_vec(vec![b'c', b'a', b'f', 0xE9]);
+        groups.get_mut(&1).unwrap().file_name = Some(file_name.clone());
+        let restored = decode(&encode(&groups).unwrap()).unwrap();
+        assert_eq!(restored[&1].file_name, Some(file_name));
+    }
+
+    #[test]
+    fn truncated_checkpoints_are_rejected() {
+        let bytes = encode(&sample()).unwrap();
+        for length in 0..bytes.len() {
+            let error = decode(&bytes[..length]).unwrap_err();
+            assert!(
+                matches!(
+                    error,
+                    CheckpointError::NotACheckpoint | CheckpointError::Truncated
+                ),
+                "{length}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_magic_is_rejected() {
+        let mut bytes = encode(&sample()).unwrap();
+        bytes[0] = b'X';
+        assert!(matches!(
+            decode(&bytes),
+            Err(CheckpointError::NotACheckpoint)
+        ));
+    }
+
+    #[test]
+    fn trailing_data_is_rejected() {
+        let mut bytes = encode(&sample()).unwrap();
+        bytes.push(0);
+        assert!(matches!(decode(&bytes), Err(CheckpointError::Corrupt(_))));
+    }
+
+    #[test]
+    fn packets_after_the_last_packet_are_rejected() {
+        let mut groups = HashMap::new();
+        add(&mut groups, data(0, 3, false));
+        groups.get_mut(&0).unwrap().expected_number_of_packets = Some(2);
+        assert!(matches!(
+            decode(&encode(&groups).unwrap()),
+            Err(CheckpointError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.checkpoint");
+        assert!(load(&path).unwrap().is_none());
+
+        let groups = sample();
+        save(&groups, &path).unwrap();
+        assert_same_state(&groups, &load(&path).unwrap().unwrap());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(load(&path).is_err());
+        assert!(load(&PathBuf::from(dir.path())).is_err());
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn arbitrary_packets_round_trip(packets: Vec<Data>) -> bool {
+        let mut groups: HashMap<u8, PacketGroup> = HashMap::new();
+        for packet in packets {
+            // Packets that are inconsistent with earlier ones are
+            // rejected, which is fine here.
+            let _ = groups
+                .entry(packet.file_id)
+                .or_default()
+                .process_packet(Packet::Data(packet));
+        }
+        let restored = decode(&encode(&groups).unwrap()).unwrap();
+        assert_same_state(&groups, &restored);
+        true
+    }
+}
@@ -1,41 +1,644 @@
-use std::{collections::HashMap, io};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
-use crate::{packet_group::PacketGroup, packets::Packet};
+use crate::{
+    checkpoint::{self, CheckpointError},
+    manifest::{Manifest, ManifestEntry},
+    output::{OutputConfig, WriteError},
+    packet_group::{
+        self, ConflictPolicy, FileProgress, FileReader, GapReport, HeaderChangePolicy, PacketGroup,
+        PacketOutcome, PacketStats, ProcessError,
+    },
+    packet_store::StorageBackend,
+    packets::{Packet, PacketRef},
+};
+
+/// What happened when we tried to write each file in a `FileManager`.
+/// Every list is in order of file ID.
+#[derive(Debug, Default)]
+pub struct WriteReport {
+    pub written: Vec<(u8, PathBuf)>,
+    /// Files we didn't write because they already existed and the
+    /// collision policy was `Skip`.
+    pub skipped: Vec<u8>,
+    pub failed: Vec<(u8, WriteError)>,
+    /// Groups we didn't try to write because they don't count as files
+    /// (see `FileManager::stray_file_ids`).
+    pub stray: Vec<u8>,
+}
+
+impl WriteReport {
+    #[must_use]
+    pub const fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// What every file in a `FileManager` is still waiting for.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GapSummary {
+    /// A gap report for each file we've seen at least one packet for.
+    pub files: BTreeMap<u8, GapReport>,
+    /// How many files we haven't seen yet (stray groups don't count;
+    /// see `FileManager::stray_file_ids`), or `None` if we don't know
+    /// how many files the server sends.
+    pub unseen_files: Option<usize>,
+}
+
+impl GapSummary {
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.unseen_files
+            .is_none_or(|unseen_files| unseen_files == 0)
+            && self.files.values().all(GapReport::is_complete)
+    }
+}
+
+impl Display for GapSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        if let Some(unseen_files) = self.unseen_files.filter(|&unseen_files| unseen_files > 0) {
+            lines.push(format!("{unseen_files} file(s) not seen yet"));
+        }
+        for (file_id, report) in &self.files {
+            if !report.is_complete() {
+                lines.push(format!("file {file_id}: {report}"));
+            }
+        }
+        if lines.is_empty() {
+            write!(f, "complete")
+        } else {
+            write!(f, "{}", lines.join("\n"))
+        }
+    }
+}
+
+/// How far along the downloads in a `FileManager` are. The totals
+/// leave out the stray groups (see `FileManager::stray_file_ids`).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Progress {
+    /// The progress of each group we've seen at least one packet for,
+    /// stray or not.
+    pub files: BTreeMap<u8, FileProgress>,
+    pub stray_files: BTreeSet<u8>,
+    /// How many files the server sends, if we know.
+    pub expected_files: Option<usize>,
+}
+
+impl Progress {
+    // The files that aren't strays.
+    fn counted_files(&self) -> impl Iterator<Item = &FileProgress> {
+        self.files
+            .iter()
+            .filter(|(file_id, _)| !self.stray_files.contains(file_id))
+            .map(|(_, file)| file)
+    }
+
+    #[must_use]
+    pub fn packets_received(&self) -> usize {
+        self.counted_files().map(|file| file.packets_received).sum()
+    }
+
+    #[must_use]
+    pub fn bytes_received(&self) -> usize {
+        self.counted_files().map(|file| file.bytes_received).sum()
+    }
+
+    /// How many of the files that aren't strays we've seen.
+    #[must_use]
+    pub fn files_seen(&self) -> usize {
+        self.counted_files().count()
+    }
+
+    /// The total number of packets in all the files, which we only
+    /// know once we've seen every file's last packet.
+    #[must_use]
+    pub fn expected_packets(&self) -> Option<usize> {
+        let seen_every_file = self
+            .expected_files
+            .is_some_and(|expected_files| self.files_seen() >= expected_files);
+        if !seen_every_file {
+            return None;
+        }
+        self.counted_files().map(|file| file.expected_packets).sum()
+    }
+
+    /// The percentage of all packets we've received, or `None` if we
+    /// don't know how many packets there are yet.
+    #[must_use]
+    pub fn percentage(&self) -> Option<f64> {
+        self.expected_packets().map(|expected_packets| {
+            packet_group::percentage(self.packets_received(), expected_packets)
+        })
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let packets_received = self.packets_received();
+        match self.expected_packets() {
+            Some(expected_packets) => write!(
+                f,
+                "{packets_received}/{expected_packets} packets ({:.1}%)",
+                packet_group::percentage(packets_received, expected_packets)
+            )?,
+            None => write!(f, "{packets_received} packets")?,
+        }
+        write!(f, ", {} bytes", self.bytes_received())?;
+        if let Some(expected_files) = self.expected_files {
+            write!(f, ", {}/{expected_files} files seen", self.files_seen())?;
+        }
+        Ok(())
+    }
+}
+
+/// Something that happened to a file while a `FileManager` was
+/// processing a packet.
+#[derive(Debug)]
+pub enum FileEvent {
+    /// We now have the header and every data packet for the file.
+    Completed { file_id: u8 },
+    /// We wrote the completed file as soon as it was complete (see
+    /// `FileManager::with_write_on_complete`) and freed its packets.
+    /// The path is `None` if the collision policy skipped it.
+    Written { file_id: u8, path: Option<PathBuf> },
+    /// We couldn't write the completed file. We keep its packets, so
+    /// `write_all_files` will try again, unless writing the part we'd
+    /// already streamed to disk failed (see `WriteError::StreamFailed`).
+    WriteFailed { file_id: u8, error: WriteError },
+}
+
+/// How a `FileManager` decides that it has received every file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExpectedFiles {
+    /// The server sends exactly this many files, so we're done once
+    /// that many files (header and data) are complete. Packets for any
+    /// other file IDs don't hold us up.
+    Exactly(usize),
+    /// We don't know how many files the server sends, so we're done
+    /// once every file we've seen is complete and no packets have
+    /// arrived for `idle_period`.
+    Unknown { idle_period: Duration },
+}
+
+impl Default for ExpectedFiles {
+    /// The classroom server sends three files.
+    fn default() -> Self {
+        Self::Exactly(3)
+    }
+}
 
 #[derive(Default)]
 pub struct FileManager {
     // The key will be a file ID, and the value will
     // be the associated PacketGroup.
     map: HashMap<u8, PacketGroup>,
+    expected_files: ExpectedFiles,
+    conflict_policy: ConflictPolicy,
+    header_change_policy: HeaderChangePolicy,
+    // Where each file keeps its packets until they're written.
+    storage: StorageBackend,
+    // Where to write files as soon as they're complete, if at all.
+    write_on_complete: Option<OutputConfig>,
+    events: Vec<FileEvent>,
+    last_packet_at: Option<Instant>,
 }
 
 impl FileManager {
+    #[must_use]
+    pub fn new(expected_files: ExpectedFiles) -> Self {
+        Self {
+            expected_files,
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn with_expected_files(number_of_files: usize) -> Self {
+        Self::new(ExpectedFiles::Exactly(number_of_files))
+    }
+
+    #[must_use]
+    pub fn with_unknown_file_count(idle_period: Duration) -> Self {
+        Self::new(ExpectedFiles::Unknown { idle_period })
+    }
+
+    /// Use `conflict_policy` for packets that arrive twice with
+    /// different contents.
+    #[must_use]
+    pub const fn with_conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    /// Use `header_change_policy` for headers that try to change the
+    /// name of a file.
+    #[must_use]
+    pub const fn with_header_change_policy(
+        mut self,
+        header_change_policy: HeaderChangePolicy,
+    ) -> Self {
+        self.header_change_policy = header_change_policy;
+        self
+    }
+
+    /// Keep each file's packets in a store of the given kind until
+    /// they're written, instead of in memory.
+    #[must_use]
+    pub fn with_storage(mut self, storage: StorageBackend) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Write each file as described by `config` as soon as it's
+    /// complete, instead of waiting for `write_all_files`, and then
+    /// free its packets. This means a file isn't lost if we crash or
+    /// are interrupted while other files are still downloading.
+    #[must_use]
+    pub fn with_write_on_complete(mut self, config: OutputConfig) -> Self {
+        self.write_on_complete = Some(config);
+        self
+    }
+
+    #[must_use]
+    pub const fn expected_files(&self) -> ExpectedFiles {
+        self.expected_files
+    }
+
+    /// The state of the file with this ID, or `None` if we haven't
+    /// received any packets for it.
+    #[must_use]
+    pub fn packet_group(&self, file_id: u8) -> Option<&PacketGroup> {
+        self.map.get(&file_id)
+    }
+
+    /// The packet counters for all of the files combined.
+    #[must_use]
+    pub fn stats(&self) -> PacketStats {
+        let mut stats = PacketStats::default();
+        for packet_group in self.map.values() {
+            stats += packet_group.stats();
+        }
+        stats
+    }
+
     #[must_use]
     pub fn received_all_packets(&self) -> bool {
-        // We have to check that we've seen all three files,
-        // and that each file is "done", i.e., we've received all
-        // of the packets for that file.
-        self.map.len() == 3 && self.map.values().all(PacketGroup::received_all_packets)
+        self.received_all_packets_at(Instant::now())
+    }
+
+    /// Like `received_all_packets`, but treats `now` as the current
+    /// time when checking the idle period of `ExpectedFiles::Unknown`.
+    /// Stray groups (see `stray_file_ids`) never hold us up.
+    #[must_use]
+    pub fn received_all_packets_at(&self, now: Instant) -> bool {
+        let mut files = self.counted_files().into_values();
+        match self.expected_files {
+            ExpectedFiles::Exactly(number_of_files) => {
+                files
+                    .filter(|packet_group| packet_group.is_complete())
+                    .count()
+                    >= number_of_files
+            }
+            ExpectedFiles::Unknown { idle_period } => {
+                files.all(PacketGroup::is_complete)
+                    && self.last_packet_at.is_some_and(|last_packet_at| {
+                        now.saturating_duration_since(last_packet_at) >= idle_period
+                    })
+            }
+        }
+    }
+
+    /// The IDs of the groups that don't count as files: the ones whose
+    /// header hasn't arrived (so we couldn't write them anyway), and,
+    /// once `ExpectedFiles::Exactly(n)` files are complete, every other
+    /// incomplete one. This way packets with an unexpected file ID
+    /// don't keep us waiting or make `write_all_files` fail.
+    ///
+    /// A group stops being a stray if its header arrives.
+    #[must_use]
+    pub fn stray_file_ids(&self) -> BTreeSet<u8> {
+        let have_every_file = match self.expected_files {
+            ExpectedFiles::Exactly(number_of_files) => {
+                self.map
+                    .values()
+                    .filter(|packet_group| packet_group.is_complete())
+                    .count()
+                    >= number_of_files
+            }
+            ExpectedFiles::Unknown { .. } => false,
+        };
+        self.map
+            .iter()
+            .filter(|(_, packet_group)| {
+                packet_group.header_missing() || (have_every_file && !packet_group.is_complete())
+            })
+            .map(|(&file_id, _)| file_id)
+            .collect()
+    }
+
+    // The groups that aren't strays, in order of file ID.
+    fn counted_files(&self) -> BTreeMap<u8, &PacketGroup> {
+        let stray_file_ids = self.stray_file_ids();
+        self.map
+            .iter()
+            .filter(|(file_id, _)| !stray_file_ids.contains(file_id))
+            .map(|(&file_id, packet_group)| (file_id, packet_group))
+            .collect()
+    }
+
+    /// Report what's still missing for every file, e.g., to diagnose a
+    /// stalled transfer or to ask the server to resend packets.
+    #[must_use]
+    pub fn gap_summary(&self) -> GapSummary {
+        let unseen_files = match self.expected_files {
+            ExpectedFiles::Exactly(number_of_files) => {
+                Some(number_of_files.saturating_sub(self.counted_files().len()))
+            }
+            ExpectedFiles::Unknown { .. } => None,
+        };
+        GapSummary {
+            files: self
+                .map
+                .iter()
+                .map(|(&file_id, packet_group)| (file_id, packet_group.gap_report()))
+                .collect(),
+            unseen_files,
+        }
+    }
+
+    /// A manifest of every complete file, with its size, number of
+    /// packets and SHA-256 hash (see `PacketGroup::digest`). Files that
+    /// are still missing packets are left out.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read a complete file's packets
+    /// back from its packet store to hash them.
+    pub fn manifest(&self) -> Result<Manifest, WriteError> {
+        let mut files = Vec::new();
+        for (file_id, packet_group) in self.counted_files() {
+            let (true, Some(file_name), Some(packets)) = (
+                packet_group.is_complete(),
+                &packet_group.file_name,
+                packet_group.expected_number_of_packets,
+            ) else {
+                continue;
+            };
+            let digest = packet_group.digest()?;
+            files.push(ManifestEntry::new(
+                file_id,
+                file_name,
+                digest.size,
+                packets,
+                digest.sha256_hex(),
+            ));
+        }
+        Ok(Manifest { files })
+    }
+
+    #[must_use]
+    pub fn progress(&self) -> Progress {
+        let expected_files = match self.expected_files {
+            ExpectedFiles::Exactly(number_of_files) => Some(number_of_files),
+            ExpectedFiles::Unknown { .. } => None,
+        };
+        Progress {
+            files: self
+                .map
+                .iter()
+                .map(|(&file_id, packet_group)| (file_id, packet_group.progress()))
+                .collect(),
+            stray_files: self.stray_file_ids(),
+            expected_files,
+        }
     }
 
     fn packet_group_for_file_id(&mut self, file_id: u8) -> &mut PacketGroup {
-        self.map.entry(file_id).or_default()
+        let conflict_policy = self.conflict_policy;
+        let header_change_policy = self.header_change_policy;
+        let storage = &self.storage;
+        self.map.entry(file_id).or_insert_with(|| {
+            PacketGroup::default()
+                .with_conflict_policy(conflict_policy)
+                .with_header_change_policy(header_change_policy)
+                .with_store(storage.create_store())
+        })
     }
 
-    pub fn process_packet(&mut self, packet: Packet) {
-        self.packet_group_for_file_id(packet.file_id())
-            .process_packet(packet);
+    /// Add `packet` to the group for its file ID; see
+    /// `PacketGroup::process_packet`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the packet is inconsistent with the packets
+    /// we've already seen for its file ID; see `ProcessError`.
+    ///
+    /// If this packet completes its file we record a
+    /// `FileEvent::Completed` (followed by a `Written` or `WriteFailed`
+    /// event if we're writing files as soon as they're complete); use
+    /// `take_events` to collect them.
+    pub fn process_packet(&mut self, packet: Packet) -> Result<PacketOutcome, ProcessError> {
+        self.process_with(packet.file_id(), |packet_group| {
+            packet_group.process_packet(packet)
+        })
     }
 
+    /// Like `process_packet`, but for a packet that's still in the
+    /// receive buffer; see `PacketGroup::process_packet_ref`.
+    ///
     /// # Errors
     ///
-    /// Will return `Err` if there is a problem writing any of the
-    /// downloaded files.
-    pub fn write_all_files(&self) -> io::Result<()> {
-        for packet_group in self.map.values() {
-            packet_group.write_file()?;
+    /// Will return the same errors as `process_packet`.
+    pub fn process_packet_ref(
+        &mut self,
+        packet: PacketRef<'_>,
+    ) -> Result<PacketOutcome, ProcessError> {
+        self.process_with(packet.file_id(), |packet_group| {
+            packet_group.process_packet_ref(packet)
+        })
+    }
+
+    fn process_with(
+        &mut self,
+        file_id: u8,
+        process: impl FnOnce(&mut PacketGroup) -> Result<PacketOutcome, ProcessError>,
+    ) -> Result<PacketOutcome, ProcessError> {
+        self.last_packet_at = Some(Instant::now());
+        let packet_group = self.packet_group_for_file_id(file_id);
+        let was_complete = packet_group.is_complete();
+        let outcome = process(packet_group)?;
+        if was_complete || !packet_group.is_complete() {
+            return Ok(outcome);
         }
-        Ok(())
+
+        self.events.push(FileEvent::Completed { file_id });
+        if let (Some(config), Some(packet_group)) =
+            (&self.write_on_complete, self.map.get_mut(&file_id))
+        {
+            self.events
+                .push(match packet_group.finish(file_id, config) {
+                    Ok(path) => FileEvent::Written { file_id, path },
+                    Err(error) => FileEvent::WriteFailed { file_id, error },
+                });
+        }
+        Ok(outcome)
+    }
+
+    /// Take the events that have happened since the last call, oldest
+    /// first.
+    pub fn take_events(&mut self) -> Vec<FileEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Stream the file with this ID to disk as far as we can; see
+    /// `PacketGroup::write_prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if we couldn't create or write to the file.
+    pub fn write_prefix(
+        &mut self,
+        file_id: u8,
+        config: &OutputConfig,
+    ) -> Result<usize, WriteError> {
+        self.map.get_mut(&file_id).map_or(Ok(0), |packet_group| {
+            packet_group.write_prefix(file_id, config)
+        })
+    }
+
+    /// Save everything we've received so far to a checkpoint file at
+    /// `path` (see the `checkpoint` module), replacing any earlier
+    /// checkpoint there.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't write the checkpoint. Any
+    /// earlier checkpoint is left as it was.
+    pub fn save_checkpoint(&self, path: &Path) -> io::Result<()> {
+        checkpoint::save(&self.map, path)
+    }
+
+    /// Restore the files saved by `save_checkpoint`, so that we only
+    /// need the packets that were still missing. The restored files
+    /// replace any we already have with the same IDs and use this
+    /// `FileManager`'s policies and storage; the packet counters start
+    /// from zero.
+    ///
+    /// Returns `false` if there's no checkpoint at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read the checkpoint or it isn't
+    /// valid, in which case nothing is restored.
+    pub fn load_checkpoint(&mut self, path: &Path) -> Result<bool, CheckpointError> {
+        let Some(groups) = checkpoint::load(path)? else {
+            return Ok(false);
+        };
+        let mut restored = Vec::with_capacity(groups.len());
+        for (file_id, mut packet_group) in groups {
+            packet_group.conflict_policy = self.conflict_policy;
+            packet_group.header_change_policy = self.header_change_policy;
+            if self.storage != StorageBackend::Memory {
+                packet_group.move_to_store(self.storage.create_store())?;
+            }
+            restored.push((file_id, packet_group));
+        }
+        self.map.extend(restored);
+        Ok(true)
+    }
+
+    // The complete files we still hold every packet of, and their names,
+    // in order of file ID.
+    fn complete_files(&self) -> Vec<(&OsStr, &PacketGroup)> {
+        let packet_groups: BTreeMap<u8, &PacketGroup> = self
+            .map
+            .iter()
+            .filter(|(_, packet_group)| {
+                packet_group.is_complete() && packet_group.packets_written() == 0
+            })
+            .map(|(&file_id, packet_group)| (file_id, packet_group))
+            .collect();
+        packet_groups
+            .into_values()
+            .filter_map(|packet_group| Some((packet_group.file_name.as_deref()?, packet_group)))
+            .collect()
+    }
+
+    /// The name (from the header packet) and contents of every complete
+    /// file, in order of file ID, without touching the disk. Files that
+    /// are still missing packets are left out, and so are files we've
+    /// already written to disk (e.g., with `with_write_on_complete`),
+    /// since we no longer have their packets.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read a packet back from the
+    /// packet store.
+    pub fn completed_files(&self) -> Result<Vec<(OsString, Vec<u8>)>, WriteError> {
+        self.complete_files()
+            .into_iter()
+            .map(|(file_name, packet_group)| {
+                let mut contents = Vec::with_capacity(packet_group.bytes_received);
+                packet_group.write_to(&mut contents)?;
+                Ok((file_name.to_os_string(), contents))
+            })
+            .collect()
+    }
+
+    /// Like `completed_files`, but with a reader for each file instead
+    /// of a copy of its contents. Errors reading from the packet store
+    /// come from the readers.
+    ///
+    /// # Errors
+    ///
+    /// Won't return `Err` in practice: we only make readers for files
+    /// we hold every packet of (see `PacketGroup::reader`).
+    pub fn completed_file_readers(&self) -> Result<Vec<(&OsStr, FileReader<'_>)>, WriteError> {
+        self.complete_files()
+            .into_iter()
+            .map(|(file_name, packet_group)| Ok((file_name, packet_group.reader()?)))
+            .collect()
+    }
+
+    /// Try to write every downloaded file as described by `config`.
+    /// Files are written in order of their file IDs, so if two files
+    /// end up with the same name the collision policy is applied to
+    /// the one with the larger ID. Stray groups (see `stray_file_ids`)
+    /// are only listed in the report.
+    ///
+    /// This never stops early: a file that can't be written (e.g.,
+    /// because it's still missing packets) is recorded in the report's
+    /// `failed` list and we move on to the next one. This makes it safe
+    /// to call on shutdown to save whatever is complete.
+    #[must_use]
+    pub fn write_all_files(&mut self, config: &OutputConfig) -> WriteReport {
+        let stray_file_ids = self.stray_file_ids();
+        let mut packet_groups: Vec<(&u8, &mut PacketGroup)> = self
+            .map
+            .iter_mut()
+            .filter(|(file_id, _)| !stray_file_ids.contains(file_id))
+            .collect();
+        packet_groups.sort_unstable_by_key(|&(&file_id, _)| file_id);
+
+        let mut report = WriteReport {
+            stray: stray_file_ids.into_iter().collect(),
+            ..WriteReport::default()
+        };
+        for (&file_id, packet_group) in packet_groups {
+            match packet_group.write_file(file_id, config) {
+                Ok(Some(path)) => report.written.push((file_id, path)),
+                Ok(None) => report.skipped.push(file_id),
+                Err(e) => report.failed.push((file_id, e)),
+            }
+        }
+        report
     }
 }
 
@@ -58,7 +661,7 @@ mod process_packet_tests {
         };
 
         let mut file_manager = FileManager::default();
-        file_manager.process_packet(Packet::Header(header));
+        file_manager.process_packet(Packet::Header(header)).unwrap();
 
         let map = file_manager.map;
         assert_eq!(map.len(), 1);
@@ -83,7 +686,9 @@ mod process_packet_tests {
         };
 
         let mut file_manager = FileManager::default();
-        file_manager.process_packet(Packet::Data(data_packet));
+        file_manager
+            .process_packet(Packet::Data(data_packet))
+            .unwrap();
 
         let map = file_manager.map;
         assert_eq!(map.len(), 1);
@@ -92,7 +697,10 @@ mod process_packet_tests {
         assert_eq!(None, packet_group.file_name);
         assert_eq!(None, packet_group.expected_number_of_packets);
         assert_eq!(1, packet_group.packets.len());
-        assert_eq!(bytes, packet_group.packets[&packet_number]);
+        assert_eq!(
+            bytes,
+            &*packet_group.packets.get(packet_number).unwrap().unwrap()
+        );
     }
 
     #[test]
@@ -109,7 +717,9 @@ mod process_packet_tests {
         };
 
         let mut file_manager = FileManager::default();
-        file_manager.process_packet(Packet::Data(data_packet));
+        file_manager
+            .process_packet(Packet::Data(data_packet))
+            .unwrap();
 
         let map = file_manager.map;
         assert_eq!(map.len(), 1);
@@ -121,29 +731,50 @@ mod process_packet_tests {
             packet_group.expected_number_of_packets
         );
         assert_eq!(1, packet_group.packets.len());
-        assert_eq!(bytes, packet_group.packets[&packet_number]);
+        assert_eq!(
+            bytes,
+            &*packet_group.packets.get(packet_number).unwrap().unwrap()
+        );
+    }
+
+    #[test]
+    fn exposes_packet_groups() {
+        let mut file_manager = FileManager::default();
+        file_manager
+            .process_packet(Header::new(4, "test_file.txt").unwrap().into())
+            .unwrap();
+        let packet_group = file_manager.packet_group(4).unwrap();
+        assert_eq!(
+            packet_group.file_name(),
+            Some(OsString::from("test_file.txt").as_os_str())
+        );
+        assert!(file_manager.packet_group(5).is_none());
     }
 }
 
 #[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[expect(
+    clippy::needless_pass_by_value,
+    reason = "quickcheck properties take their inputs by value"
+)]
 #[cfg(test)]
 mod quickcheck_tests {
 
-    use crate::packets::{Data, Header};
+    use crate::packets::{Data, FileNameMode, Header};
 
     use super::*;
 
     #[quickcheck_macros::quickcheck]
     fn header_doesnt_crash(packet: Header) -> bool {
         let mut file_manager = FileManager::default();
-        file_manager.process_packet(Packet::Header(packet));
+        file_manager.process_packet(Packet::Header(packet)).unwrap();
         true
     }
 
     #[quickcheck_macros::quickcheck]
     fn data_doesnt_crash(packet: Data) -> bool {
         let mut file_manager = FileManager::default();
-        file_manager.process_packet(Packet::Data(packet));
+        file_manager.process_packet(Packet::Data(packet)).unwrap();
         true
     }
 
@@ -151,7 +782,9 @@ mod quickcheck_tests {
     fn header_sets_name(packet: Header) -> bool {
         let mut file_manager = FileManager::default();
         assert_eq!(None, file_manager.map.get(&packet.file_id));
-        file_manager.process_packet(Packet::Header(packet.clone()));
+        file_manager
+            .process_packet(Packet::Header(packet.clone()))
+            .unwrap();
         assert_eq!(
             0,
             file_manager.map.get(&packet.file_id).unwrap().packets.len()
@@ -163,7 +796,9 @@ mod quickcheck_tests {
     fn data_add_vec(packet: Data) -> bool {
         let mut file_manager = FileManager::default();
         assert_eq!(None, file_manager.map.get(&packet.file_id));
-        file_manager.process_packet(Packet::Data(packet.clone()));
+        file_manager
+            .process_packet(Packet::Data(packet.clone()))
+            .unwrap();
 
         let group = file_manager.map.get(&packet.file_id).unwrap();
         assert_eq!(None, group.file_name);
@@ -176,7 +811,26 @@ mod quickcheck_tests {
             assert_eq!(None, group.expected_number_of_packets);
         }
         assert_eq!(1, group.packets.len());
-        *group.packets.get(&packet.packet_number).unwrap() == packet.data
+        *group.packets.get(packet.packet_number).unwrap().unwrap() == packet.data
+    }
+
+    // Everything but the copying should be the same on the borrowed
+    // path `main` uses.
+    #[quickcheck_macros::quickcheck]
+    fn borrowed_packets_match_owned_packets(packets: Vec<Packet>) -> bool {
+        let mut owned = FileManager::default();
+        let mut borrowed = FileManager::default();
+        for packet in packets {
+            let bytes = packet.encode();
+            let packet_ref = PacketRef::parse(&bytes, FileNameMode::Lenient).unwrap();
+            let outcome = borrowed.process_packet_ref(packet_ref);
+            assert_eq!(owned.process_packet(packet), outcome);
+        }
+        assert_eq!(
+            owned.completed_files().unwrap(),
+            borrowed.completed_files().unwrap()
+        );
+        owned.map == borrowed.map
     }
 }
 
@@ -216,7 +870,7 @@ mod all_packets_tests {
         let mut file_manager = FileManager::default();
         packets.shuffle(&mut rng);
         for p in packets {
-            file_manager.process_packet(p);
+            file_manager.process_packet(p).unwrap();
         }
 
         assert_eq!(1, file_manager.map.len());
@@ -224,7 +878,7 @@ mod all_packets_tests {
         assert_eq!(Some(file_name), group.file_name);
         assert_eq!(Some(3), group.expected_number_of_packets);
         for packet_number in 0..num_packets {
-            let data = group.packets.get(&packet_number).unwrap();
+            let data = group.packets.get(packet_number).unwrap().unwrap();
             assert_eq!(2, data.len());
             let val: u8 = (packet_number % 100).try_into().unwrap();
             assert_eq!(val, data[0]);
@@ -232,3 +886,948 @@ mod all_packets_tests {
         }
     }
 }
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod write_all_files_tests {
+    use std::fs;
+
+    use crate::{
+        output::{CollisionPolicy, WriteError},
+        test_helpers::{add_file, data, header, output_dir},
+    };
+
+    use super::FileManager;
+
+    #[test]
+    fn same_name_from_two_files_gets_suffix() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = FileManager::default();
+        add_file(&mut file_manager, 9, "small.txt", b"second");
+        add_file(&mut file_manager, 2, "small.txt", b"first");
+
+        let config = config.with_collision_policy(CollisionPolicy::AddSuffix);
+        let report = file_manager.write_all_files(&config);
+        assert!(report.is_success());
+
+        let path = output_dir.path();
+        assert_eq!("first", fs::read_to_string(path.join("small.txt")).unwrap());
+        assert_eq!(
+            "second",
+            fs::read_to_string(path.join("small-1.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn template_includes_file_id() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = FileManager::default();
+        add_file(&mut file_manager, 9, "small.txt", b"second");
+        add_file(&mut file_manager, 2, "small.txt", b"first");
+
+        let config = config.with_template("{file_id}-{name}".parse().unwrap());
+        let report = file_manager.write_all_files(&config);
+        assert!(report.is_success());
+
+        let path = output_dir.path();
+        assert_eq!(
+            "first",
+            fs::read_to_string(path.join("2-small.txt")).unwrap()
+        );
+        assert_eq!(
+            "second",
+            fs::read_to_string(path.join("9-small.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn incomplete_file_doesnt_stop_other_writes() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = FileManager::default();
+        add_file(&mut file_manager, 2, "complete.txt", b"done");
+        file_manager
+            .process_packet(header(1, "incomplete.txt"))
+            .unwrap();
+
+        let report = file_manager.write_all_files(&config);
+
+        assert!(!report.is_success());
+        assert_eq!(
+            vec![(2, output_dir.path().join("complete.txt"))],
+            report.written
+        );
+        assert!(report.skipped.is_empty());
+        assert_eq!(1, report.failed.len());
+        assert!(matches!(report.failed[0], (1, WriteError::UnknownLength)));
+        assert!(!output_dir.path().join("incomplete.txt").exists());
+    }
+
+    #[test]
+    fn stray_groups_are_not_written() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = FileManager::with_expected_files(1);
+        add_file(&mut file_manager, 2, "small.txt", b"done");
+        // No header, and a file that turned up after the one we expected.
+        file_manager.process_packet(data(5, 0, true, b"?")).unwrap();
+        file_manager.process_packet(header(6, "extra.txt")).unwrap();
+
+        let report = file_manager.write_all_files(&config);
+
+        assert!(report.is_success());
+        assert_eq!(
+            vec![(2, output_dir.path().join("small.txt"))],
+            report.written
+        );
+        assert_eq!(vec![5, 6], report.stray);
+    }
+
+    #[test]
+    fn skipped_files_are_reported() {
+        let (output_dir, config) = output_dir();
+        fs::write(output_dir.path().join("small.txt"), "old").unwrap();
+        let mut file_manager = FileManager::default();
+        add_file(&mut file_manager, 2, "small.txt", b"new");
+
+        let config = config.with_collision_policy(CollisionPolicy::Skip);
+        let report = file_manager.write_all_files(&config);
+
+        assert!(report.is_success());
+        assert!(report.written.is_empty());
+        assert_eq!(vec![2], report.skipped);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod expected_files_tests {
+    use std::time::{Duration, Instant};
+
+    use crate::test_helpers::{data, header};
+
+    use super::FileManager;
+
+    fn add_file(file_manager: &mut FileManager, file_id: u8) {
+        crate::test_helpers::add_file(
+            file_manager,
+            file_id,
+            &format!("file-{file_id}.txt"),
+            &[file_id],
+        );
+    }
+
+    #[test]
+    fn default_expects_three_files() {
+        let mut file_manager = FileManager::default();
+        add_file(&mut file_manager, 0);
+        add_file(&mut file_manager, 1);
+        assert!(!file_manager.received_all_packets());
+        add_file(&mut file_manager, 2);
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn single_file() {
+        let mut file_manager = FileManager::with_expected_files(1);
+        assert!(!file_manager.received_all_packets());
+        add_file(&mut file_manager, 7);
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn forty_files() {
+        let mut file_manager = FileManager::with_expected_files(40);
+        for file_id in 0..39 {
+            add_file(&mut file_manager, file_id);
+        }
+        assert!(!file_manager.received_all_packets());
+        add_file(&mut file_manager, 39);
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn stray_file_ids_dont_block_completion() {
+        let mut file_manager = FileManager::with_expected_files(2);
+        add_file(&mut file_manager, 0);
+        file_manager
+            .process_packet(data(200, 3, false, &[1]))
+            .unwrap();
+        assert!(!file_manager.received_all_packets());
+        add_file(&mut file_manager, 1);
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn files_need_their_header() {
+        let mut file_manager = FileManager::with_expected_files(1);
+        file_manager.process_packet(data(0, 0, true, &[1])).unwrap();
+        assert!(!file_manager.received_all_packets());
+        file_manager.process_packet(header(0, "late.txt")).unwrap();
+        assert!(file_manager.received_all_packets());
+    }
+
+    #[test]
+    fn unknown_count_only_counts_files_with_a_header() {
+        let idle_period = Duration::from_secs(2);
+        let mut file_manager = FileManager::with_unknown_file_count(idle_period);
+        add_file(&mut file_manager, 0);
+        file_manager
+            .process_packet(data(1, 3, false, &[1]))
+            .unwrap();
+        assert_eq!(file_manager.stray_file_ids(), [1].into());
+        assert!(file_manager.received_all_packets_at(Instant::now() + idle_period));
+
+        file_manager.process_packet(header(1, "late.txt")).unwrap();
+        assert!(file_manager.stray_file_ids().is_empty());
+        assert!(!file_manager.received_all_packets_at(Instant::now() + idle_period));
+    }
+
+    #[test]
+    fn incomplete_files_are_strays_once_every_file_is_complete() {
+        let mut file_manager = FileManager::with_expected_files(1);
+        file_manager.process_packet(header(1, "extra.txt")).unwrap();
+        assert!(file_manager.stray_file_ids().is_empty());
+        add_file(&mut file_manager, 0);
+        assert_eq!(file_manager.stray_file_ids(), [1].into());
+    }
+
+    #[test]
+    fn unknown_count_waits_for_idle_period() {
+        let idle_period = Duration::from_secs(2);
+        let mut file_manager = FileManager::with_unknown_file_count(idle_period);
+        assert!(!file_manager.received_all_packets_at(Instant::now() + idle_period));
+
+        add_file(&mut file_manager, 0);
+        add_file(&mut file_manager, 1);
+        let now = Instant::now();
+        assert!(!file_manager.received_all_packets_at(now));
+        assert!(file_manager.received_all_packets_at(now + idle_period));
+    }
+
+    #[test]
+    fn unknown_count_waits_for_incomplete_files() {
+        let idle_period = Duration::from_secs(2);
+        let mut file_manager = FileManager::with_unknown_file_count(idle_period);
+        add_file(&mut file_manager, 0);
+        file_manager
+            .process_packet(header(1, "incomplete.txt"))
+            .unwrap();
+        assert!(!file_manager.received_all_packets_at(Instant::now() + idle_period));
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod gap_summary_tests {
+    use std::time::Duration;
+
+    use crate::test_helpers::{data, header};
+
+    use super::FileManager;
+
+    fn add_header(file_manager: &mut FileManager, file_id: u8) {
+        file_manager
+            .process_packet(header(file_id, &format!("file-{file_id}.txt")))
+            .unwrap();
+    }
+
+    fn add_data(
+        file_manager: &mut FileManager,
+        file_id: u8,
+        packet_number: u16,
+        is_last_packet: bool,
+    ) {
+        file_manager
+            .process_packet(data(file_id, packet_number, is_last_packet, &[file_id]))
+            .unwrap();
+    }
+
+    #[test]
+    fn empty_manager_reports_unseen_files() {
+        let summary = FileManager::with_expected_files(2).gap_summary();
+        assert!(summary.files.is_empty());
+        assert_eq!(summary.unseen_files, Some(2));
+        assert!(!summary.is_complete());
+        assert_eq!(summary.to_string(), "2 file(s) not seen yet");
+    }
+
+    #[test]
+    fn unknown_count_has_no_unseen_files() {
+        let file_manager = FileManager::with_unknown_file_count(Duration::from_secs(1));
+        let summary = file_manager.gap_summary();
+        assert_eq!(summary.unseen_files, None);
+        assert!(summary.is_complete());
+    }
+
+    #[test]
+    fn reports_each_incomplete_file() {
+        let mut file_manager = FileManager::with_expected_files(3);
+        add_header(&mut file_manager, 0);
+        add_data(&mut file_manager, 0, 0, true);
+        add_data(&mut file_manager, 5, 3, true);
+        add_header(&mut file_manager, 2);
+        add_data(&mut file_manager, 2, 2, false);
+
+        let summary = file_manager.gap_summary();
+        // File 5 doesn't count until its header arrives.
+        assert_eq!(summary.unseen_files, Some(1));
+        assert!(summary.files[&0].is_complete());
+        assert_eq!(summary.files[&5].missing, vec![0..=2]);
+        assert!(summary.files[&5].header_missing);
+        assert_eq!(summary.files[&2].missing, vec![0..=1]);
+        assert!(!summary.is_complete());
+        assert_eq!(
+            summary.to_string(),
+            "1 file(s) not seen yet\n\
+             file 2: last packet missing; missing packets 0-1\n\
+             file 5: header missing; missing packets 0-2"
+        );
+    }
+
+    #[test]
+    fn complete_when_every_file_is_complete() {
+        let mut file_manager = FileManager::with_expected_files(1);
+        add_header(&mut file_manager, 4);
+        add_data(&mut file_manager, 4, 1, true);
+        add_data(&mut file_manager, 4, 0, false);
+        let summary = file_manager.gap_summary();
+        assert!(summary.is_complete());
+        assert_eq!(summary.to_string(), "complete");
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod progress_tests {
+    use std::time::Duration;
+
+    use crate::test_helpers::{data, header};
+
+    use super::FileManager;
+
+    fn add_header(file_manager: &mut FileManager, file_id: u8) {
+        file_manager
+            .process_packet(header(file_id, &format!("file-{file_id}.txt")))
+            .unwrap();
+    }
+
+    // Every packet is ten bytes long.
+    fn add_data(
+        file_manager: &mut FileManager,
+        file_id: u8,
+        packet_number: u16,
+        is_last_packet: bool,
+    ) {
+        file_manager
+            .process_packet(data(file_id, packet_number, is_last_packet, &[file_id; 10]))
+            .unwrap();
+    }
+
+    #[test]
+    fn empty_manager() {
+        let progress = FileManager::with_expected_files(2).progress();
+        assert!(progress.files.is_empty());
+        assert_eq!(progress.packets_received(), 0);
+        assert_eq!(progress.expected_packets(), None);
+        assert_eq!(progress.percentage(), None);
+        assert_eq!(progress.to_string(), "0 packets, 0 bytes, 0/2 files seen");
+    }
+
+    #[test]
+    fn total_is_unknown_until_every_file_has_been_seen() {
+        let mut file_manager = FileManager::with_expected_files(2);
+        add_header(&mut file_manager, 0);
+        add_data(&mut file_manager, 0, 0, false);
+        add_data(&mut file_manager, 0, 1, true);
+        let progress = file_manager.progress();
+        assert_eq!(progress.packets_received(), 2);
+        assert_eq!(progress.bytes_received(), 20);
+        assert_eq!(progress.expected_packets(), None);
+        assert_eq!(progress.files[&0].percentage(), Some(100.0));
+    }
+
+    #[test]
+    fn total_is_unknown_until_every_last_packet_arrives() {
+        let mut file_manager = FileManager::with_expected_files(2);
+        add_header(&mut file_manager, 0);
+        add_header(&mut file_manager, 1);
+        add_data(&mut file_manager, 0, 1, true);
+        add_data(&mut file_manager, 1, 0, false);
+        assert_eq!(file_manager.progress().expected_packets(), None);
+    }
+
+    #[test]
+    fn aggregates_every_file() {
+        let mut file_manager = FileManager::with_expected_files(2);
+        file_manager.process_packet(header(1, "small.txt")).unwrap();
+        add_header(&mut file_manager, 0);
+        add_data(&mut file_manager, 0, 1, true);
+        add_data(&mut file_manager, 1, 0, false);
+        add_data(&mut file_manager, 1, 2, true);
+
+        let progress = file_manager.progress();
+        assert_eq!(progress.files[&1].file_name, Some("small.txt".into()));
+        assert_eq!(progress.packets_received(), 3);
+        assert_eq!(progress.expected_packets(), Some(5));
+        assert_eq!(progress.bytes_received(), 30);
+        assert_eq!(progress.percentage(), Some(60.0));
+        assert_eq!(
+            progress.to_string(),
+            "3/5 packets (60.0%), 30 bytes, 2/2 files seen"
+        );
+    }
+
+    #[test]
+    fn unknown_file_count_never_knows_the_total() {
+        let mut file_manager = FileManager::with_unknown_file_count(Duration::from_secs(1));
+        add_header(&mut file_manager, 0);
+        add_data(&mut file_manager, 0, 0, true);
+        let progress = file_manager.progress();
+        assert_eq!(progress.expected_packets(), None);
+        assert_eq!(progress.to_string(), "1 packets, 10 bytes");
+    }
+
+    #[test]
+    fn strays_are_left_out_of_the_totals() {
+        let mut file_manager = FileManager::with_expected_files(1);
+        add_header(&mut file_manager, 0);
+        add_data(&mut file_manager, 0, 0, true);
+        add_data(&mut file_manager, 9, 5, false);
+
+        let progress = file_manager.progress();
+        assert_eq!(progress.stray_files, [9].into());
+        assert_eq!(progress.files.len(), 2);
+        assert_eq!(progress.packets_received(), 1);
+        assert_eq!(progress.expected_packets(), Some(1));
+        assert_eq!(
+            progress.to_string(),
+            "1/1 packets (100.0%), 10 bytes, 1/1 files seen"
+        );
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use crate::{
+        packet_group::{ConflictPolicy, PacketOutcome, PacketStats, ProcessError},
+        packets::Packet,
+    };
+
+    use super::FileManager;
+
+    // A whole file in one packet.
+    fn data(file_id: u8, data: &[u8]) -> Packet {
+        crate::test_helpers::data(file_id, 0, true, data)
+    }
+
+    #[test]
+    fn stats_cover_every_file() {
+        let mut file_manager = FileManager::default();
+        assert_eq!(
+            file_manager.process_packet(data(0, b"a")),
+            Ok(PacketOutcome::New)
+        );
+        assert_eq!(
+            file_manager.process_packet(data(1, b"b")),
+            Ok(PacketOutcome::New)
+        );
+        assert_eq!(
+            file_manager.process_packet(data(1, b"b")),
+            Ok(PacketOutcome::Duplicate)
+        );
+        assert_eq!(
+            file_manager.process_packet(data(0, b"c")),
+            Ok(PacketOutcome::Conflict)
+        );
+        assert_eq!(
+            file_manager.stats(),
+            PacketStats {
+                new: 2,
+                duplicates: 1,
+                conflicts: 1,
+                rejected: 0,
+                restarts: 0
+            }
+        );
+    }
+
+    #[test]
+    fn conflict_policy_applies_to_every_file() {
+        let mut file_manager = FileManager::default().with_conflict_policy(ConflictPolicy::Error);
+        for file_id in [3, 9] {
+            assert_eq!(
+                file_manager.process_packet(data(file_id, b"a")),
+                Ok(PacketOutcome::New)
+            );
+            assert_eq!(
+                file_manager.process_packet(data(file_id, b"b")),
+                Err(ProcessError::ConflictingPacket { packet_number: 0 })
+            );
+        }
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod header_change_tests {
+    use crate::{
+        packet_group::{HeaderChangePolicy, PacketOutcome},
+        test_helpers::header,
+    };
+
+    use super::FileManager;
+
+    #[test]
+    fn header_change_policy_applies_to_new_files() {
+        let mut file_manager =
+            FileManager::default().with_header_change_policy(HeaderChangePolicy::Restart);
+        file_manager.process_packet(header(3, "old.txt")).unwrap();
+        assert_eq!(
+            file_manager.process_packet(header(3, "new.txt")),
+            Ok(PacketOutcome::Restarted)
+        );
+        assert_eq!(file_manager.stats().restarts, 1);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod write_prefix_tests {
+    use std::fs;
+
+    use crate::test_helpers::{data, header, output_dir};
+
+    use super::FileManager;
+
+    #[test]
+    fn streams_each_file_separately() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = FileManager::with_expected_files(2);
+        for file_id in [0, 1] {
+            file_manager
+                .process_packet(header(file_id, &format!("file-{file_id}.txt")))
+                .unwrap();
+            file_manager
+                .process_packet(data(file_id, 0, file_id == 0, &[b'0' + file_id]))
+                .unwrap();
+        }
+        assert_eq!(file_manager.write_prefix(0, &config).unwrap(), 1);
+        assert_eq!(file_manager.write_prefix(7, &config).unwrap(), 0);
+        assert!(!output_dir.path().join("file-0.txt").exists());
+
+        let report = file_manager.write_all_files(&config);
+        assert_eq!(
+            fs::read(output_dir.path().join("file-0.txt")).unwrap(),
+            b"0"
+        );
+        assert!(!output_dir.path().join("file-1.txt").exists());
+        assert_eq!(report.written.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, 1);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod event_tests {
+    use std::fs;
+
+    use crate::test_helpers::{data, header, output_dir};
+
+    use super::{FileEvent, FileManager};
+
+    // The header arrives last, so that's the packet that completes the
+    // file.
+    fn add_file(file_manager: &mut FileManager, file_id: u8) {
+        file_manager
+            .process_packet(data(file_id, 0, true, &[b'0' + file_id]))
+            .unwrap();
+        file_manager
+            .process_packet(header(file_id, &format!("file-{file_id}.txt")))
+            .unwrap();
+    }
+
+    #[test]
+    fn reports_each_completed_file_once() {
+        let mut file_manager = FileManager::with_expected_files(2);
+        add_file(&mut file_manager, 4);
+        let events = file_manager.take_events();
+        assert!(matches!(events[..], [FileEvent::Completed { file_id: 4 }]));
+        assert!(file_manager.take_events().is_empty());
+
+        add_file(&mut file_manager, 4);
+        add_file(&mut file_manager, 5);
+        let events = file_manager.take_events();
+        assert!(matches!(events[..], [FileEvent::Completed { file_id: 5 }]));
+    }
+
+    #[test]
+    fn writes_files_as_they_complete() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager =
+            FileManager::with_expected_files(2).with_write_on_complete(config.clone());
+
+        add_file(&mut file_manager, 0);
+        let path = output_dir.path().join("file-0.txt");
+        let events = file_manager.take_events();
+        assert!(matches!(
+            &events[..],
+            [
+                FileEvent::Completed { file_id: 0 },
+                FileEvent::Written { file_id: 0, path: Some(written) }
+            ] if *written == path
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"0");
+        assert!(!file_manager.received_all_packets());
+
+        add_file(&mut file_manager, 1);
+        assert!(file_manager.received_all_packets());
+        let report = file_manager.write_all_files(&config);
+        assert!(report.is_success());
+        assert_eq!(report.written.len(), 2);
+        assert_eq!(fs::read(&path).unwrap(), b"0");
+    }
+
+    #[test]
+    fn reports_write_failures() {
+        let (_output_dir, config) = output_dir();
+        let mut file_manager = FileManager::with_expected_files(1).with_write_on_complete(config);
+        crate::test_helpers::add_file(&mut file_manager, 0, "../escape.txt", &[0]);
+        let events = file_manager.take_events();
+        assert!(matches!(
+            events[..],
+            [
+                FileEvent::Completed { file_id: 0 },
+                FileEvent::WriteFailed { file_id: 0, .. }
+            ]
+        ));
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod checkpoint_tests {
+    use std::fs;
+
+    use crate::{
+        output::OutputConfig, packet_group::ConflictPolicy, packets::Packet, test_helpers::header,
+    };
+
+    use super::FileManager;
+
+    // Packet 0 of file 7 is "a", packet 1 is "b", and so on.
+    fn data(packet_number: u16, is_last_packet: bool) -> Packet {
+        let contents = [b'a' + u8::try_from(packet_number).unwrap()];
+        crate::test_helpers::data(7, packet_number, is_last_packet, &contents)
+    }
+
+    #[test]
+    fn resumes_from_a_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("client.checkpoint");
+
+        let mut file_manager = FileManager::with_expected_files(1);
+        file_manager
+            .process_packet(header(7, "resumed.txt"))
+            .unwrap();
+        file_manager.process_packet(data(0, false)).unwrap();
+        file_manager.process_packet(data(2, true)).unwrap();
+        file_manager.save_checkpoint(&checkpoint).unwrap();
+        drop(file_manager);
+
+        let mut file_manager =
+            FileManager::with_expected_files(1).with_conflict_policy(ConflictPolicy::Error);
+        assert!(file_manager.load_checkpoint(&checkpoint).unwrap());
+        assert_eq!(file_manager.gap_summary().files[&7].missing, vec![1..=1]);
+        assert_eq!(file_manager.map[&7].conflict_policy, ConflictPolicy::Error);
+
+        file_manager.process_packet(data(1, false)).unwrap();
+        assert!(file_manager.received_all_packets());
+        let report = file_manager.write_all_files(&OutputConfig::new(dir.path()));
+        assert!(report.is_success());
+        assert_eq!(fs::read(dir.path().join("resumed.txt")).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn missing_checkpoint_is_not_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file_manager = FileManager::default();
+        assert!(!file_manager
+            .load_checkpoint(&dir.path().join("missing"))
+            .unwrap());
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod storage_tests {
+    use std::fs;
+
+    use crate::{
+        output::OutputConfig,
+        packet_store::StorageBackend,
+        packets::Packet,
+        test_helpers::{data, header},
+    };
+
+    use super::FileManager;
+
+    // "wwwxxxyyyzzz" in four packets, the last one first.
+    fn packets() -> Vec<Packet> {
+        let mut packets = vec![header(4, "stored.txt")];
+        packets.extend((0..4).rev().map(|packet_number| {
+            let contents = [b'w' + u8::try_from(packet_number).unwrap(); 3];
+            data(4, packet_number, packet_number == 3, &contents)
+        }));
+        packets
+    }
+
+    #[test]
+    fn every_backend_writes_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let backends = [
+            StorageBackend::Memory,
+            StorageBackend::SpillFile(dir.path().join("spill")),
+            StorageBackend::MemoryMapped(dir.path().join("mmap")),
+        ];
+        for (i, backend) in backends.into_iter().enumerate() {
+            let output_dir = dir.path().join(i.to_string());
+            let mut file_manager = FileManager::with_expected_files(1).with_storage(backend);
+            for packet in packets() {
+                file_manager.process_packet(packet).unwrap();
+            }
+            assert!(file_manager.received_all_packets());
+            let report = file_manager.write_all_files(&OutputConfig::new(&output_dir));
+            assert!(report.is_success());
+            assert_eq!(
+                fs::read(output_dir.join("stored.txt")).unwrap(),
+                b"wwwxxxyyyzzz"
+            );
+        }
+    }
+
+    #[test]
+    fn checkpoints_are_restored_into_the_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = dir.path().join("client.checkpoint");
+        let spill_dir = dir.path().join("spill");
+
+        let mut file_manager = FileManager::with_expected_files(1);
+        for packet in packets().into_iter().take(3) {
+            file_manager.process_packet(packet).unwrap();
+        }
+        file_manager.save_checkpoint(&checkpoint).unwrap();
+
+        let mut file_manager = FileManager::with_expected_files(1)
+            .with_storage(StorageBackend::SpillFile(spill_dir.clone()));
+        assert!(file_manager.load_checkpoint(&checkpoint).unwrap());
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 1);
+        for packet in packets().into_iter().skip(3) {
+            file_manager.process_packet(packet).unwrap();
+        }
+        let report = file_manager.write_all_files(&OutputConfig::new(dir.path()));
+        assert!(report.is_success());
+        assert_eq!(
+            fs::read(dir.path().join("stored.txt")).unwrap(),
+            b"wwwxxxyyyzzz"
+        );
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod manifest_tests {
+    use std::fs;
+
+    use crate::{
+        manifest::ManifestEntry,
+        test_helpers::{data, header, output_dir},
+    };
+
+    use super::FileManager;
+
+    // The SHA-256 hash of "abc".
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn abc_file_manager() -> FileManager {
+        let mut file_manager = FileManager::with_expected_files(2);
+        for packet in [
+            data(1, 1, false, b"b"),
+            header(1, "abc.txt"),
+            data(1, 2, true, b"c"),
+            data(1, 0, false, b"a"),
+            // File 2 never gets its last packet.
+            header(2, "partial.txt"),
+            data(2, 0, false, b"x"),
+        ] {
+            file_manager.process_packet(packet).unwrap();
+        }
+        file_manager
+    }
+
+    fn abc_entry() -> ManifestEntry {
+        ManifestEntry {
+            file_id: 1,
+            name: "abc.txt".to_string(),
+            name_bytes: None,
+            size: 3,
+            packets: 3,
+            sha256: ABC_SHA256.to_string(),
+        }
+    }
+
+    #[test]
+    fn lists_complete_files() {
+        let manifest = abc_file_manager().manifest().unwrap();
+        assert_eq!(manifest.files, vec![abc_entry()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_are_kept_exactly() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        use crate::packets::{FileNameMode, PacketRef};
+
+        let mut file_manager = FileManager::with_expected_files(1);
+        let datagram = b"\x00\x01caf\xe9.txt";
+        file_manager
+            .process_packet_ref(PacketRef::parse(datagram, FileNameMode::Lenient).unwrap())
+            .unwrap();
+        file_manager
+            .process_packet(data(1, 0, true, b"abc"))
+            .unwrap();
+        let manifest = file_manager.manifest().unwrap();
+        assert_eq!(
+            manifest.files,
+            vec![ManifestEntry::new(
+                1,
+                OsStr::from_bytes(b"caf\xe9.txt"),
+                3,
+                1,
+                ABC_SHA256.to_string()
+            )]
+        );
+        assert_eq!(manifest.files[0].exact_name(), b"caf\xe9.txt");
+    }
+
+    #[test]
+    fn digest_survives_writing() {
+        let (output_dir, config) = output_dir();
+        let mut file_manager = abc_file_manager().with_write_on_complete(config.clone());
+        file_manager
+            .process_packet(data(1, 0, false, b"a"))
+            .unwrap();
+        file_manager
+            .map
+            .get_mut(&1)
+            .unwrap()
+            .finish(1, &config)
+            .unwrap();
+        assert!(file_manager.map[&1].packets.is_empty());
+        assert_eq!(file_manager.manifest().unwrap().files, vec![abc_entry()]);
+        assert_eq!(fs::read(output_dir.path().join("abc.txt")).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn streamed_files_have_the_same_digest() {
+        let (_output_dir, config) = output_dir();
+        let mut file_manager = FileManager::with_expected_files(1);
+        for packet in [
+            header(1, "abc.txt"),
+            data(1, 0, false, b"a"),
+            data(1, 2, true, b"c"),
+            data(1, 1, false, b"b"),
+        ] {
+            file_manager.process_packet(packet).unwrap();
+            file_manager.write_prefix(1, &config).unwrap();
+        }
+        assert_eq!(file_manager.manifest().unwrap().files, vec![abc_entry()]);
+        let report = file_manager.write_all_files(&config);
+        assert!(report.is_success());
+        assert_eq!(file_manager.manifest().unwrap().files, vec![abc_entry()]);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod completed_files_tests {
+    use std::{ffi::OsString, io::Read};
+
+    use crate::test_helpers::{data, header, output_dir};
+
+    use super::FileManager;
+
+    fn file_manager() -> FileManager {
+        let mut file_manager = FileManager::with_expected_files(4);
+        for packet in [
+            data(7, 1, true, b"cd"),
+            header(7, "second.txt"),
+            data(7, 0, false, b"ab"),
+            header(2, "first.txt"),
+            data(2, 0, true, b"xyz"),
+            // File 3 never gets its last packet, and file 4 never gets
+            // its header.
+            header(3, "partial.txt"),
+            data(3, 0, false, b"x"),
+            data(4, 0, true, b"headless"),
+        ] {
+            file_manager.process_packet(packet).unwrap();
+        }
+        file_manager
+    }
+
+    #[test]
+    fn lists_complete_files_in_order() {
+        assert_eq!(
+            file_manager().completed_files().unwrap(),
+            vec![
+                (OsString::from("first.txt"), b"xyz".to_vec()),
+                (OsString::from("second.txt"), b"abcd".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn readers_match_contents() {
+        let file_manager = file_manager();
+        let readers: Vec<(OsString, String)> = file_manager
+            .completed_file_readers()
+            .unwrap()
+            .into_iter()
+            .map(|(file_name, mut reader)| {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents).unwrap();
+                (file_name.to_os_string(), contents)
+            })
+            .collect();
+        assert_eq!(
+            readers,
+            vec![
+                (OsString::from("first.txt"), "xyz".to_string()),
+                (OsString::from("second.txt"), "abcd".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_written_on_completion_are_left_out() {
+        let (_output_dir, config) = output_dir();
+        let mut file_manager = FileManager::with_expected_files(2).with_write_on_complete(config);
+        for packet in [
+            header(2, "first.txt"),
+            data(2, 0, true, b"xyz"),
+            // This file can't be written, so we keep its packets.
+            header(5, "../unsafe.txt"),
+            data(5, 0, true, b"kept"),
+        ] {
+            file_manager.process_packet(packet).unwrap();
+        }
+        assert_eq!(
+            file_manager.completed_files().unwrap(),
+            vec![(OsString::from("../unsafe.txt"), b"kept".to_vec())]
+        );
+        let readers = file_manager.completed_file_readers().unwrap();
+        assert_eq!(readers.len(), 1);
+        assert_eq!(readers[0].0, "../unsafe.txt");
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fmt::{self, Display},
+    path::{Component, Path, PathBuf},
+};
+
+/// The longest name (in bytes) we're willing to give a file or one of
+/// the directories it's in. This matches the per-name limit on most
+/// common file systems.
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FileNameError {
+    Empty,
+    ContainsNul,
+    AbsolutePath,
+    ParentDirectory,
+    TooLong { length: usize, maximum: usize },
+}
+
+impl Display for FileNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "file name is empty"),
+            Self::ContainsNul => write!(f, "file name contains a NUL byte"),
+            Self::AbsolutePath => write!(f, "file name is an absolute path"),
+            Self::ParentDirectory => write!(f, "file name refers to a parent directory (`..`)"),
+            Self::TooLong { length, maximum } => write!(
+                f,
+                "a name in the path is {length} bytes long but can be at most {maximum}"
+            ),
+        }
+    }
+}
+
+impl Error for FileNameError {}
+
+/// Turn a file name that came from a (possibly hostile) server into a
+/// relative path that is safe to join onto an output directory.
+///
+/// Redundant separators and `.` components are dropped, so
+/// `./docs//notes.txt` becomes `docs/notes.txt`. Names that could
+/// escape the output directory are rejected rather than "repaired".
+///
+/// # Errors
+///
+/// Will return `Err` if the name
+///   * is empty (or only contains `.` components)
+///   * contains a NUL byte
+///   * is an absolute path (or has a Windows drive prefix)
+///   * contains a `..` component
+///   * has a component longer than `MAX_FILE_NAME_LENGTH` bytes
+pub fn sanitize_file_name(file_name: &OsStr) -> Result<PathBuf, FileNameError> {
+    if file_name.as_encoded_bytes().contains(&0) {
+        return Err(FileNameError::ContainsNul);
+    }
+
+    let mut path = PathBuf::new();
+    for component in Path::new(file_name).components() {
+        match component {
+            Component::Normal(name) if name.len() > MAX_FILE_NAME_LENGTH => {
+                return Err(FileNameError::TooLong {
+                    length: name.len(),
+                    maximum: MAX_FILE_NAME_LENGTH,
+                });
+            }
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(FileNameError::ParentDirectory),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(FileNameError::AbsolutePath);
+            }
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(FileNameError::Empty);
+    }
+    Ok(path)
+}
+
+#[expect(
+    clippy::needless_pass_by_value,
+    reason = "quickcheck properties take their inputs by value"
+)]
+#[cfg(test)]
+mod sanitize_tests {
+    use std::{ffi::OsStr, path::PathBuf};
+
+    use super::{sanitize_file_name, FileNameError, MAX_FILE_NAME_LENGTH};
+
+    fn sanitize(name: &str) -> Result<PathBuf, FileNameError> {
+        sanitize_file_name(OsStr::new(name))
+    }
+
+    #[test]
+    fn plain_name_is_unchanged() {
+        assert_eq!(sanitize("small.txt"), Ok(PathBuf::from("small.txt")));
+    }
+
+    #[test]
+    fn subdirectories_are_kept() {
+        assert_eq!(
+            sanitize("docs/notes.txt"),
+            Ok(PathBuf::from("docs").join("notes.txt"))
+        );
+    }
+
+    #[test]
+    fn current_directory_components_are_dropped() {
+        assert_eq!(
+            sanitize("./docs/./notes.txt"),
+            Ok(PathBuf::from("docs").join("notes.txt"))
+        );
+    }
+
+    #[test]
+    fn empty_name_is_rejected() {
+        assert_eq!(sanitize(""), Err(FileNameError::Empty));
+        assert_eq!(sanitize("./."), Err(FileNameError::Empty));
+    }
+
+    #[test]
+    fn parent_directory_is_rejected() {
+        assert_eq!(sanitize("../../etc/x"), Err(FileNameError::ParentDirectory));
+        assert_eq!(
+            sanitize("docs/../../x"),
+            Err(FileNameError::ParentDirectory)
+        );
+    }
+
+    #[test]
+    fn absolute_path_is_rejected() {
+        assert_eq!(sanitize("/etc/passwd"), Err(FileNameError::AbsolutePath));
+    }
+
+    #[test]
+    fn nul_byte_is_rejected() {
+        assert_eq!(sanitize("small\0.txt"), Err(FileNameError::ContainsNul));
+    }
+
+    #[test]
+    fn long_name_is_rejected() {
+        let name = "x".repeat(MAX_FILE_NAME_LENGTH + 1);
+        assert_eq!(
+            sanitize(&name),
+            Err(FileNameError::TooLong {
+                length: MAX_FILE_NAME_LENGTH + 1,
+                maximum: MAX_FILE_NAME_LENGTH
+            })
+        );
+        let name = "x".repeat(MAX_FILE_NAME_LENGTH);
+        assert_eq!(sanitize(&name), Ok(PathBuf::from(name)));
+    }
+
+    #[test]
+    fn length_limit_is_per_component() {
+        let name = "x".repeat(MAX_FILE_NAME_LENGTH);
+        assert_eq!(
+            sanitize(&format!("{name}/{name}")),
+            Ok(PathBuf::from(&name).join(&name))
+        );
+        assert_eq!(
+            sanitize(&format!("docs/{name}x")),
+            Err(FileNameError::TooLong {
+                length: MAX_FILE_NAME_LENGTH + 1,
+                maximum: MAX_FILE_NAME_LENGTH
+            })
+        );
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn sanitized_names_stay_relative(name: String) -> bool {
+        sanitize(&name).map_or(true, |path| {
+            path.is_relative()
+                && path
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+        })
+    }
+}
@@ -0,0 +1,443 @@
+//! A journal of received packets. Every packet the `FileManager`
+//! accepts is appended to the journal, exactly as it arrived, so after a
+//! crash replaying the journal rebuilds the state we had.
+//!
+//! Rejected packets aren't journaled, so the rebuilt `PacketStats` only
+//! count the rejections since the restart.
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! magic              b"SFCJRNL1"
+//! for each packet:
+//!   length           u32, the length of the encoded packet
+//!   checksum         u32, the FNV-1a hash of the encoded packet
+//!   packet           the packet as it's sent over the wire
+//! ```
+//!
+//! A crash while appending can leave a partial record at the end of the
+//! journal. Replaying drops that record and cuts it off the file so that
+//! new records follow the last good one. The packet in it, like one we
+//! crashed before appending, just counts as missing again.
+
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    file_manager::FileManager,
+    packets::{FileNameMode, PacketRef},
+};
+
+const MAGIC: &[u8; 8] = b"SFCJRNL1";
+
+const RECORD_HEADER_LEN: usize = 8;
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// The file doesn't start with the journal magic bytes.
+    NotAJournal,
+    /// A record before the end of the journal is damaged, so we can't
+    /// trust anything after it.
+    Corrupt {
+        offset: u64,
+        reason: String,
+    },
+}
+
+impl Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+            Self::NotAJournal => write!(f, "not a journal file"),
+            Self::Corrupt { offset, reason } => {
+                write!(f, "the journal is corrupt at byte {offset}: {reason}")
+            }
+        }
+    }
+}
+
+impl Error for JournalError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for JournalError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// What we found when replaying a journal.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Replay {
+    /// How many packets were replayed.
+    pub packets: usize,
+    /// How many bytes of a partial record at the end of the journal
+    /// were thrown away.
+    pub discarded_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    sync: bool,
+    // The record being appended, kept so we don't allocate one for
+    // every packet.
+    record: Vec<u8>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if it doesn't exist, and
+    /// replay every packet in it into `file_manager`. Packets that
+    /// `file_manager` rejects are skipped, just as they were when they
+    /// first arrived.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read or write the journal, if
+    /// the file isn't a journal, or if a record before the end of the
+    /// journal is damaged. A damaged last record isn't an error.
+    pub fn open(
+        path: &Path,
+        file_manager: &mut FileManager,
+    ) -> Result<(Self, Replay), JournalError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut replay = Replay::default();
+
+        let valid_len = if file_len < MAGIC.len() as u64 {
+            // Either a new journal, or we crashed while creating it.
+            let mut start = Vec::new();
+            file.read_to_end(&mut start)?;
+            if !MAGIC.starts_with(&start) {
+                return Err(JournalError::NotAJournal);
+            }
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(MAGIC)?;
+            MAGIC.len() as u64
+        } else {
+            let mut reader = BufReader::new(&file);
+            let mut magic = [0; MAGIC.len()];
+            reader.read_exact(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(JournalError::NotAJournal);
+            }
+            replay_records(&mut reader, file_manager, &mut replay)?
+        };
+
+        replay.discarded_bytes = file.metadata()?.len() - valid_len;
+        if replay.discarded_bytes > 0 {
+            file.set_len(valid_len)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok((
+            Self {
+                file,
+                sync: false,
+                record: Vec::new(),
+            },
+            replay,
+        ))
+    }
+
+    /// Make sure each packet is on disk (`fsync`) before `append`
+    /// returns. This is much slower, but a packet can't be lost even if
+    /// the machine loses power.
+    #[must_use]
+    pub const fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Add a packet to the end of the journal, given as the datagram it
+    /// arrived in. Call this once the `FileManager` has accepted the
+    /// packet; replaying a packet it rejected would only be rejected
+    /// again.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't write to the journal, or (with
+    /// kind `InvalidInput`) if the datagram is too large to record.
+    pub fn append(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let length = u32::try_from(datagram.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet is too large"))?;
+        self.record.clear();
+        self.record.extend_from_slice(&length.to_le_bytes());
+        self.record
+            .extend_from_slice(&checksum(datagram).to_le_bytes());
+        self.record.extend_from_slice(datagram);
+        // A single write, so a crash leaves at most one partial record.
+        self.file.write_all(&self.record)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Throw away every record, e.g., because a checkpoint now holds
+    /// everything the journal did.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't truncate the journal.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(MAGIC.len() as u64)?;
+        self.file.seek(SeekFrom::End(0))?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+// Replay the records that follow the magic bytes, returning the length
+// of the journal up to the end of the last good record.
+fn replay_records(
+    reader: &mut impl BufRead,
+    file_manager: &mut FileManager,
+    replay: &mut Replay,
+) -> Result<u64, JournalError> {
+    let mut offset = MAGIC.len() as u64;
+    loop {
+        let mut header = [0; RECORD_HEADER_LEN];
+        if !read_record_part(reader, &mut header)? {
+            return Ok(offset);
+        }
+        let [l0, l1, l2, l3, c0, c1, c2, c3] = header;
+        let length = u32::from_le_bytes([l0, l1, l2, l3]);
+        let expected_checksum = u32::from_le_bytes([c0, c1, c2, c3]);
+
+        let mut encoded = Vec::new();
+        reader
+            .by_ref()
+            .take(u64::from(length))
+            .read_to_end(&mut encoded)?;
+        if encoded.len() < length as usize {
+            // The last record was only partly written.
+            return Ok(offset);
+        }
+
+        let at_end = reader.fill_buf()?.is_empty();
+        let corrupt = |reason: String| JournalError::Corrupt { offset, reason };
+        if checksum(&encoded) != expected_checksum {
+            if at_end {
+                // A torn write that happened to fill the whole record.
+                return Ok(offset);
+            }
+            return Err(corrupt("checksum mismatch".to_string()));
+        }
+        let packet = PacketRef::parse(&encoded, FileNameMode::Lenient)
+            .map_err(|e| corrupt(format!("invalid packet: {e}")))?;
+
+        // Every packet in the journal was accepted once, so in the same
+        // order it's accepted again. If storing it fails now, it's just
+        // missing.
+        let _ = file_manager.process_packet_ref(packet);
+        replay.packets += 1;
+        offset += (RECORD_HEADER_LEN + encoded.len()) as u64;
+    }
+}
+
+// Fill `buf` from `reader`, returning `false` if the reader ended first.
+fn read_record_part(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// 32-bit FNV-1a. This only needs to catch torn and damaged records, not
+// deliberate tampering.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod journal_tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        file_manager::FileManager,
+        packet_group::PacketStats,
+        packets::Packet,
+        test_helpers::{data, header},
+    };
+
+    use super::{Journal, JournalError, Replay, MAGIC};
+
+    fn packets() -> Vec<Packet> {
+        vec![
+            header(1, "journal.txt"),
+            data(1, 0, false, b"abc"),
+            data(1, 2, true, b"ghi"),
+        ]
+    }
+
+    fn write_journal(path: &Path, packets: &[Packet]) {
+        let (mut journal, _) = Journal::open(path, &mut FileManager::default()).unwrap();
+        for packet in packets {
+            journal.append(&packet.encode()).unwrap();
+        }
+    }
+
+    // Rejected packets aren't journaled, so we don't count them again.
+    fn assert_same_state(expected: &FileManager, actual: &FileManager) {
+        assert_eq!(expected.gap_summary(), actual.gap_summary());
+        assert_eq!(expected.progress(), actual.progress());
+        let stats = |file_manager: &FileManager| PacketStats {
+            rejected: 0,
+            ..file_manager.stats()
+        };
+        assert_eq!(stats(expected), stats(actual));
+    }
+
+    #[test]
+    fn new_journal_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        let (_, replay) = Journal::open(&path, &mut FileManager::default()).unwrap();
+        assert_eq!(replay, Replay::default());
+        assert_eq!(fs::read(&path).unwrap(), MAGIC);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn replay_rebuilds_the_same_state(packets: Vec<Packet>) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        let (mut journal, _) = Journal::open(&path, &mut FileManager::default()).unwrap();
+        let mut expected = FileManager::default();
+        let mut accepted = 0;
+        for packet in packets {
+            let datagram = packet.encode();
+            if expected.process_packet(packet).is_ok() {
+                journal.append(&datagram).unwrap();
+                accepted += 1;
+            }
+        }
+        drop(journal);
+
+        let mut actual = FileManager::default();
+        let (_, replay) = Journal::open(&path, &mut actual).unwrap();
+        assert_eq!(replay.packets, accepted);
+        assert_same_state(&expected, &actual);
+        true
+    }
+
+    #[test]
+    fn partial_last_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        let packets = packets();
+        write_journal(&path, &packets[..2]);
+        let good_len = fs::metadata(&path).unwrap().len();
+        write_journal(&path, &packets[2..]);
+        let full = fs::read(&path).unwrap();
+
+        for len in 0..full.len() {
+            fs::write(&path, &full[..len]).unwrap();
+            let mut file_manager = FileManager::default();
+            let (mut journal, replay) = Journal::open(&path, &mut file_manager).unwrap();
+            let len = len as u64;
+            if len >= good_len {
+                assert_eq!(replay.packets, 2);
+                assert_eq!(replay.discarded_bytes, len - good_len);
+            }
+
+            // New records go right after the last good one.
+            journal.append(&packets[2].encode()).unwrap();
+            drop(journal);
+            if len >= good_len {
+                assert_eq!(fs::read(&path).unwrap(), full);
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_last_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        write_journal(&path, &packets());
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let mut file_manager = FileManager::default();
+        let (_, replay) = Journal::open(&path, &mut file_manager).unwrap();
+        assert_eq!(replay.packets, 2);
+        assert_eq!(
+            file_manager.gap_summary().files[&1].last_packet_number,
+            None
+        );
+    }
+
+    #[test]
+    fn damaged_middle_record_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        write_journal(&path, &packets());
+        let mut bytes = fs::read(&path).unwrap();
+        // The file ID of the header packet.
+        bytes[MAGIC.len() + 8 + 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let result = Journal::open(&path, &mut FileManager::default());
+        assert!(matches!(
+            result,
+            Err(JournalError::Corrupt { offset: 8, .. })
+        ));
+        // The journal is left alone so someone can look at it.
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        fs::write(&path, "not a journal").unwrap();
+        assert!(matches!(
+            Journal::open(&path, &mut FileManager::default()),
+            Err(JournalError::NotAJournal)
+        ));
+        fs::write(&path, "abc").unwrap();
+        assert!(matches!(
+            Journal::open(&path, &mut FileManager::default()),
+            Err(JournalError::NotAJournal)
+        ));
+    }
+
+    #[test]
+    fn clear_removes_every_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.journal");
+        let (mut journal, _) = Journal::open(&path, &mut FileManager::default()).unwrap();
+        for packet in &packets() {
+            journal.append(&packet.encode()).unwrap();
+        }
+        journal.clear().unwrap();
+        journal.append(&packets()[0].encode()).unwrap();
+        drop(journal);
+
+        let (_, replay) = Journal::open(&path, &mut FileManager::default()).unwrap();
+        assert_eq!(replay.packets, 1);
+    }
+}
@@ -9,3 +9,13 @@
 pub mod packets;
 pub mod file_manager;
 pub mod packet_group;
+pub mod packet_store;
+pub mod file_name;
+pub mod output;
+pub mod checkpoint;
+pub mod journal;
+pub mod manifest;
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod test_helpers;
@@ -1,53 +1,635 @@
 use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fmt::{self, Display},
+    fs,
     io::{self, Write},
     net::UdpSocket,
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 use rust_segmented_file_client::{
-    file_manager::FileManager,
-    packets::{Packet, PacketParseError},
+    checkpoint::CheckpointError,
+    file_manager::{ExpectedFiles, FileEvent, FileManager},
+    journal::{Journal, JournalError},
+    manifest::{Manifest, Verification},
+    output::{OutputConfig, WriteError},
+    packet_group::{ConflictPolicy, HeaderChangePolicy, PacketOutcome, ProcessError},
+    packet_store::StorageBackend,
+    packets::{FileNameMode, PacketRef},
 };
 
+const USAGE: &str = "\
+usage: rust-segmented-file-client [options]
+
+options:
+  --output-dir DIR          write files into DIR (default: .)
+  --name-template TEMPLATE  name files using TEMPLATE, which can use
+                            {file_id} and {name} (default: {name})
+  --on-collision POLICY     what to do if a file already exists: one of
+                            overwrite, skip, suffix, or error
+                            (default: suffix)
+  --expected-files N        how many files the server sends, or `unknown`
+                            to stop once every file is complete and the
+                            server has gone quiet (default: 3)
+  --idle-period SECONDS     how long the server has to be quiet before we
+                            stop when the number of files is unknown
+                            (default: 2)
+  --on-conflict POLICY      what to do if a packet arrives twice with
+                            different contents: one of keep-first,
+                            keep-last, or error (default: keep-first)
+  --on-header-change POLICY what to do if a header changes the name of a
+                            file: reject the header, or restart the file
+                            (default: reject)
+  --lenient-names           accept file names that aren't UTF-8 (from
+                            legacy servers, say) instead of ignoring
+                            their headers; on Unix the raw bytes are kept
+  --stream                  write the start of each file to disk as soon
+                            as it arrives instead of holding every file
+                            in memory until the end
+  --write-on-complete       write each file as soon as it's complete
+                            instead of waiting for every file
+  --sync                    make sure each file is on disk (fsync) before
+                            and after moving it into place, and each
+                            packet is on disk in the journal (if there
+                            is one) before we go on
+  --checkpoint FILE         save what has been received so far to FILE,
+                            and pick up from FILE if it already exists
+  --checkpoint-interval SECONDS
+                            how often to save the checkpoint (default: 5)
+  --journal FILE            record every packet we accept in FILE, and
+                            replay FILE if it already exists
+  --storage KIND            where to keep packets until their file is
+                            written: memory, spill-file (a temporary
+                            file), or mmap (a memory-mapped temporary
+                            file) (default: memory)
+  --storage-dir DIR         where to put the temporary files for
+                            `--storage` (default: the system's temporary
+                            directory)
+  --manifest FILE           write a JSON manifest of the files we received
+                            (with their sizes and SHA-256 hashes) to FILE
+  --verify FILE             check the files we received against the
+                            manifest in FILE, failing if any are
+                            different, missing, or unexpected
+  --help                    print this message";
+
+const DEFAULT_NUMBER_OF_FILES: usize = 3;
+const DEFAULT_IDLE_PERIOD: Duration = Duration::from_secs(2);
+const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+// Building the progress line is too slow to do for every packet.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Args {
+    output_config: OutputConfig,
+    expected_files: ExpectedFiles,
+    conflict_policy: ConflictPolicy,
+    header_change_policy: HeaderChangePolicy,
+    file_name_mode: FileNameMode,
+    stream: bool,
+    write_on_complete: bool,
+    checkpoint: Option<PathBuf>,
+    checkpoint_interval: Duration,
+    journal: Option<PathBuf>,
+    manifest: Option<PathBuf>,
+    verify: Option<PathBuf>,
+    sync: bool,
+    storage: StorageBackend,
+}
+
 // TODO: Maybe use this as a chance to explore an alternative
 //   error handling system like `anyhow`.
 #[derive(Debug)]
 enum ClientError {
     IoError(std::io::Error),
-    PacketParseError(PacketParseError),
+    CheckpointError(CheckpointError),
+    JournalError(JournalError),
+    ManifestFailed(WriteError),
+    ProcessError(ProcessError),
+    Usage(String),
+    VerificationFailed(Verification),
+    WriteFailures(Vec<(u8, WriteError)>),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {e}"),
+            Self::CheckpointError(e) => write!(f, "couldn't load checkpoint: {e}"),
+            Self::JournalError(e) => write!(f, "couldn't replay journal: {e}"),
+            Self::ManifestFailed(e) => write!(f, "couldn't build the manifest: {e}"),
+            Self::ProcessError(e) => write!(f, "inconsistent packet: {e}"),
+            Self::Usage(message) => write!(f, "{message}\n\n{USAGE}"),
+            Self::VerificationFailed(verification) => {
+                write!(f, "files don't match the manifest: {verification}")
+            }
+            Self::WriteFailures(failures) => {
+                write!(f, "couldn't write {} file(s):", failures.len())?;
+                for (file_id, e) in failures {
+                    write!(f, "\n  file {file_id}: {e}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            Self::CheckpointError(e) => Some(e),
+            Self::JournalError(e) => Some(e),
+            Self::ManifestFailed(e) => Some(e),
+            Self::ProcessError(e) => Some(e),
+            Self::Usage(_) | Self::VerificationFailed(_) | Self::WriteFailures(_) => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for ClientError {
     fn from(e: std::io::Error) -> Self {
-        ClientError::IoError(e)
+        Self::IoError(e)
+    }
+}
+
+impl From<CheckpointError> for ClientError {
+    fn from(e: CheckpointError) -> Self {
+        Self::CheckpointError(e)
+    }
+}
+
+impl From<JournalError> for ClientError {
+    fn from(e: JournalError) -> Self {
+        Self::JournalError(e)
     }
 }
 
-impl From<PacketParseError> for ClientError {
-    fn from(e: PacketParseError) -> Self {
-        Self::PacketParseError(e)
+impl From<ProcessError> for ClientError {
+    fn from(e: ProcessError) -> Self {
+        Self::ProcessError(e)
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage_error(message: impl Into<String>) -> ClientError {
+    ClientError::Usage(message.into())
+}
+
+fn parse_value<T>(flag: &str, value: &OsString) -> Result<T, ClientError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .to_str()
+        .ok_or_else(|| usage_error(format!("the value for `{flag}` must be UTF-8")))?
+        .parse()
+        .map_err(|e| usage_error(format!("invalid value for `{flag}`: {e}")))
+}
+
+fn parse_seconds(flag: &str, value: &OsString) -> Result<Duration, ClientError> {
+    let seconds: f64 = parse_value(flag, value)?;
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(usage_error(format!(
+            "the value for `{flag}` must be a positive number of seconds"
+        ))),
+    }
+}
+
+// Parse the command line arguments, returning `None` if we were asked
+// to print the help message.
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Option<Args>, ClientError> {
+    let mut output_dir = OsString::from(".");
+    let mut template = None;
+    let mut on_collision = None;
+    // `None` means the number of files is unknown.
+    let mut number_of_files = Some(DEFAULT_NUMBER_OF_FILES);
+    let mut idle_period = DEFAULT_IDLE_PERIOD;
+    let mut conflict_policy = ConflictPolicy::default();
+    let mut header_change_policy = HeaderChangePolicy::default();
+    let mut file_name_mode = FileNameMode::Strict;
+    let mut stream = false;
+    let mut write_on_complete = false;
+    let mut sync = false;
+    let mut checkpoint = None;
+    let mut checkpoint_interval = DEFAULT_CHECKPOINT_INTERVAL;
+    let mut journal = None;
+    let mut manifest = None;
+    let mut verify = None;
+    let mut storage_kind = String::from("memory");
+    let mut storage_dir = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .to_str()
+            .ok_or_else(|| usage_error(format!("unexpected argument {arg:?}")))?;
+        match flag {
+            "--help" => return Ok(None),
+            "--lenient-names" => {
+                file_name_mode = FileNameMode::Lenient;
+                continue;
+            }
+            "--stream" => {
+                stream = true;
+                continue;
+            }
+            "--write-on-complete" => {
+                write_on_complete = true;
+                continue;
+            }
+            "--sync" => {
+                sync = true;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| usage_error(format!("missing value for `{flag}`")))?;
+        match flag {
+            "--output-dir" => output_dir = value,
+            "--name-template" => template = Some(parse_value(flag, &value)?),
+            "--on-collision" => on_collision = Some(parse_value(flag, &value)?),
+            "--expected-files" if value == "unknown" => number_of_files = None,
+            "--expected-files" => number_of_files = Some(parse_value(flag, &value)?),
+            "--idle-period" => idle_period = parse_seconds(flag, &value)?,
+            "--checkpoint" => checkpoint = Some(PathBuf::from(value)),
+            "--checkpoint-interval" => checkpoint_interval = parse_seconds(flag, &value)?,
+            "--journal" => journal = Some(PathBuf::from(value)),
+            "--manifest" => manifest = Some(PathBuf::from(value)),
+            "--verify" => verify = Some(PathBuf::from(value)),
+            "--storage" => storage_kind = parse_value(flag, &value)?,
+            "--storage-dir" => storage_dir = Some(PathBuf::from(value)),
+            "--on-conflict" => conflict_policy = parse_value(flag, &value)?,
+            "--on-header-change" => header_change_policy = parse_value(flag, &value)?,
+            _ => return Err(usage_error(format!("unexpected argument `{flag}`"))),
+        }
+    }
+
+    let mut output_config = OutputConfig::new(output_dir).with_sync(sync);
+    if let Some(template) = template {
+        output_config = output_config.with_template(template);
+    }
+    if let Some(on_collision) = on_collision {
+        output_config = output_config.with_collision_policy(on_collision);
     }
+    let storage_dir = storage_dir.unwrap_or_else(env::temp_dir);
+    let storage = match storage_kind.as_str() {
+        "memory" => StorageBackend::Memory,
+        "spill-file" => StorageBackend::SpillFile(storage_dir),
+        "mmap" => StorageBackend::MemoryMapped(storage_dir),
+        _ => {
+            return Err(usage_error(format!(
+                "invalid value for `--storage`: {storage_kind}"
+            )))
+        }
+    };
+    let expected_files = number_of_files.map_or(
+        ExpectedFiles::Unknown { idle_period },
+        ExpectedFiles::Exactly,
+    );
+    Ok(Some(Args {
+        output_config,
+        expected_files,
+        conflict_policy,
+        header_change_policy,
+        file_name_mode,
+        stream,
+        write_on_complete,
+        checkpoint,
+        checkpoint_interval,
+        journal,
+        manifest,
+        verify,
+        sync,
+        storage,
+    }))
 }
 
-fn main() -> Result<(), ClientError> {
+fn run() -> Result<(), ClientError> {
+    let Some(args) = parse_args(env::args_os().skip(1))? else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    // Load this before receiving anything so that a bad manifest doesn't
+    // waste a whole download.
+    let expected_manifest = args.verify.as_deref().map(Manifest::load).transpose()?;
+    let (mut file_manager, mut journal) = prepare(&args)?;
+    let sock = connect(&args)?;
+    receive(&sock, &args, &mut file_manager, &mut journal)?;
+    finish(&args, &mut file_manager, journal, expected_manifest)
+}
+
+// Set up a `FileManager` as `args` asks, picking up from the checkpoint
+// and journal if there are any.
+fn prepare(args: &Args) -> Result<(FileManager, Option<Journal>), ClientError> {
+    // Nothing else should be writing to the output directory, so any
+    // temporary files there are left over from a run that was cut short.
+    let stale_temp_files = args.output_config.remove_stale_temp_files()?;
+    if !stale_temp_files.is_empty() {
+        eprintln!(
+            "removed {} stale temporary file(s) from an earlier run",
+            stale_temp_files.len()
+        );
+    }
+
+    let mut file_manager = FileManager::new(args.expected_files)
+        .with_conflict_policy(args.conflict_policy)
+        .with_header_change_policy(args.header_change_policy)
+        .with_storage(args.storage.clone());
+    if args.write_on_complete {
+        file_manager = file_manager.with_write_on_complete(args.output_config.clone());
+    }
+    if let Some(checkpoint) = &args.checkpoint {
+        if file_manager.load_checkpoint(checkpoint)? {
+            println!(
+                "resuming from {}: {}",
+                checkpoint.display(),
+                file_manager.progress()
+            );
+        }
+    }
+    // The journal holds the packets that arrived after the checkpoint
+    // was saved, so it's replayed on top of the checkpoint.
+    let journal = match &args.journal {
+        Some(path) => {
+            let (journal, replay) = Journal::open(path, &mut file_manager)?;
+            if replay.packets > 0 {
+                println!(
+                    "replayed {} packet(s) from {}",
+                    replay.packets,
+                    path.display()
+                );
+            }
+            if replay.discarded_bytes > 0 {
+                eprintln!(
+                    "warning: dropped a partial record ({} bytes) from the end of {}",
+                    replay.discarded_bytes,
+                    path.display()
+                );
+            }
+            Some(journal.with_sync(args.sync))
+        }
+        None => None,
+    };
+    Ok((file_manager, journal))
+}
+
+// Ask the server to start sending.
+fn connect(args: &Args) -> io::Result<UdpSocket> {
     let sock = UdpSocket::bind("0.0.0.0:7077")?;
 
     let remote_addr = "127.0.0.1:6014";
     sock.connect(remote_addr)?;
-    let mut buf = [0; 1028];
+    let buf = [0; 1028];
 
     let _ = sock.send(&buf[..1028]);
 
-    let mut file_manager = FileManager::default();
+    // Wake up regularly so we notice when the server goes quiet, and so
+    // we keep saving checkpoints while nothing arrives.
+    let read_timeout = match (args.expected_files, &args.checkpoint) {
+        (ExpectedFiles::Unknown { idle_period }, Some(_)) => {
+            Some(idle_period.min(args.checkpoint_interval))
+        }
+        (ExpectedFiles::Unknown { idle_period }, None) => Some(idle_period),
+        (ExpectedFiles::Exactly(_), Some(_)) => Some(args.checkpoint_interval),
+        (ExpectedFiles::Exactly(_), None) => None,
+    };
+    sock.set_read_timeout(read_timeout)?;
+    Ok(sock)
+}
+
+// Receive packets until we have every file, saving checkpoints,
+// journaling packets and streaming files to disk along the way if
+// `args` asks us to.
+fn receive(
+    sock: &UdpSocket,
+    args: &Args,
+    file_manager: &mut FileManager,
+    journal: &mut Option<Journal>,
+) -> Result<(), ClientError> {
+    let mut buf = [0; 1028];
+    let mut progress = ProgressLine::default();
+    let mut last_checkpoint = Instant::now();
 
     while !file_manager.received_all_packets() {
-        let len = sock.recv(&mut buf)?;
-        let packet: Packet = buf[..len].try_into()?;
-        print!(".");
+        if let Some(checkpoint) = &args.checkpoint {
+            if last_checkpoint.elapsed() >= args.checkpoint_interval {
+                file_manager.save_checkpoint(checkpoint)?;
+                if let Some(journal) = journal {
+                    journal.clear()?;
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+        let len = match sock.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let datagram = &buf[..len];
+        // A stray or damaged datagram shouldn't cost us the download.
+        let packet = match PacketRef::parse(datagram, args.file_name_mode) {
+            Ok(packet) => packet,
+            Err(e) => {
+                progress.warn(format_args!("ignoring a {len}-byte datagram: {e}"))?;
+                continue;
+            }
+        };
+        let file_id = packet.file_id();
+        let outcome = file_manager.process_packet_ref(packet);
+        if outcome.is_ok() {
+            if let Some(journal) = journal {
+                journal.append(datagram)?;
+            }
+        }
+        match outcome {
+            Ok(PacketOutcome::Restarted) => {
+                progress.warn(format_args!(
+                    "file {file_id} was restarted under a new name"
+                ))?;
+            }
+            Ok(PacketOutcome::DroppedLaterPackets(dropped)) => {
+                progress.warn(format_args!(
+                    "dropped packet(s) {dropped:?} for file {file_id}: they come after its \
+                     last packet"
+                ))?;
+            }
+            Ok(_) => {}
+            // We were asked to treat conflicting packets as errors, or
+            // we can't store packets at all.
+            Err(
+                e @ (ProcessError::ConflictingPacket { .. } | ProcessError::StoreFailed { .. }),
+            ) => return Err(e.into()),
+            Err(e) => progress.warn(format_args!("ignoring packet for file {file_id}: {e}"))?,
+        }
+        if args.stream {
+            file_manager
+                .write_prefix(file_id, &args.output_config)
+                .map_err(|e| ClientError::WriteFailures(vec![(file_id, e)]))?;
+        }
+        for event in file_manager.take_events() {
+            match event {
+                FileEvent::Completed { file_id } => {
+                    progress.println(format_args!("file {file_id} is complete"))?;
+                }
+                FileEvent::Written {
+                    file_id,
+                    path: Some(path),
+                } => {
+                    progress.println(format_args!("wrote file {file_id} to {}", path.display()))?
+                }
+                FileEvent::Written {
+                    file_id,
+                    path: None,
+                } => progress.println(format_args!(
+                    "skipped file {file_id} because it already exists"
+                ))?,
+                FileEvent::WriteFailed { file_id, error } => {
+                    progress.warn(format_args!("couldn't write file {file_id} yet: {error}"))?;
+                }
+            }
+        }
+        if progress.is_due() {
+            progress.show(file_manager.progress())?;
+        }
+    }
+    progress.show(file_manager.progress())?;
+    progress.finish();
+    Ok(())
+}
+
+// Write every file, write and check the manifest, and clean up the
+// checkpoint and journal once everything is on disk.
+fn finish(
+    args: &Args,
+    file_manager: &mut FileManager,
+    mut journal: Option<Journal>,
+    expected_manifest: Option<Manifest>,
+) -> Result<(), ClientError> {
+    println!("received {}", file_manager.stats());
+
+    let report = file_manager.write_all_files(&args.output_config);
+    for file_id in &report.stray {
+        eprintln!(
+            "warning: didn't write file {file_id}: its header never arrived or we weren't expecting it"
+        );
+    }
+    let received_manifest = file_manager
+        .manifest()
+        .map_err(ClientError::ManifestFailed)?;
+    if let Some(manifest) = &args.manifest {
+        received_manifest.save(manifest)?;
+    }
+    if report.is_success() {
+        // Everything is on disk, so there's nothing left to resume.
+        drop(journal);
+        for path in args.checkpoint.iter().chain(&args.journal) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        match expected_manifest.map(|expected| received_manifest.verify(&expected)) {
+            Some(verification) if !verification.is_success() => {
+                Err(ClientError::VerificationFailed(verification))
+            }
+            Some(verification) => {
+                println!("{verification}");
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    } else {
+        if let Some(checkpoint) = &args.checkpoint {
+            file_manager.save_checkpoint(checkpoint)?;
+            if let Some(journal) = &mut journal {
+                journal.clear()?;
+            }
+        }
+        Err(ClientError::WriteFailures(report.failed))
+    }
+}
+
+// The progress line that we keep rewriting (with `\r`) on stdout while
+// packets arrive. Anything else we print while it's showing goes
+// through here, so that the line is cleared first rather than the
+// message being tacked on to the end of it.
+#[derive(Default)]
+struct ProgressLine {
+    // How many characters are showing.
+    width: usize,
+    last_shown: Option<Instant>,
+}
+
+impl ProgressLine {
+    fn is_due(&self) -> bool {
+        self.last_shown
+            .is_none_or(|last_shown| last_shown.elapsed() >= PROGRESS_INTERVAL)
+    }
+
+    fn show(&mut self, progress: impl Display) -> io::Result<()> {
+        let line = format!("received {progress}");
+        let width = line.chars().count();
+        // Cover up the end of a longer line.
+        let padding = self.width.saturating_sub(width);
+        print!("\r{line}{:padding$}", "");
         io::stdout().flush()?;
-        file_manager.process_packet(packet);
+        self.width = width;
+        self.last_shown = Some(Instant::now());
+        Ok(())
     }
 
-    file_manager.write_all_files()?;
+    fn clear(&mut self) -> io::Result<()> {
+        if self.width > 0 {
+            print!("\r{:width$}\r", "", width = self.width);
+            io::stdout().flush()?;
+            self.width = 0;
+            // Put it back as soon as the next packet arrives.
+            self.last_shown = None;
+        }
+        Ok(())
+    }
 
-    Ok(())
+    fn println(&mut self, message: impl Display) -> io::Result<()> {
+        self.clear()?;
+        println!("{message}");
+        Ok(())
+    }
+
+    fn warn(&mut self, message: impl Display) -> io::Result<()> {
+        self.clear()?;
+        eprintln!("warning: {message}");
+        Ok(())
+    }
+
+    // Leave the line showing and move on to the next one.
+    fn finish(&mut self) {
+        if self.width > 0 {
+            println!();
+            self.width = 0;
+        }
+    }
 }
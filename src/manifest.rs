@@ -0,0 +1,364 @@
+//! A JSON record of the files we've received, so that whatever consumes
+//! them can check that it got what we did, and so that we can check
+//! that we got what somebody expected.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// One reassembled file.
+///
+/// An expected manifest only needs each file's `name`, `size` and
+/// `sha256`; the other fields default to zero (or nothing) and aren't
+/// checked.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    #[serde(default)]
+    pub file_id: u8,
+    /// The name from the header packet (with any bytes that aren't
+    /// valid UTF-8 replaced), not the name the file was written under.
+    pub name: String,
+    /// The exact bytes of the name, if it isn't valid UTF-8 (which
+    /// only happens with `FileNameMode::Lenient` on Unix).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_bytes: Option<Vec<u8>>,
+    pub size: u64,
+    #[serde(default)]
+    pub packets: usize,
+    /// The SHA-256 hash of the file as lowercase hexadecimal.
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The files in order of their file IDs.
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// The manifest as pretty-printed JSON.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to `writer` fails.
+    pub fn write_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Write the manifest to the file at `path`, replacing it if it
+    /// exists.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't create or write the file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+
+    /// Read a manifest written by `save`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read the file, or (with kind
+    /// `InvalidData`) if it isn't a valid manifest.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Compare the files in this manifest with the ones in `expected`,
+    /// matching them up by the exact bytes of their names.
+    #[must_use]
+    pub fn verify(&self, expected: &Self) -> Verification {
+        let mut unmatched: BTreeMap<&[u8], &ManifestEntry> = expected
+            .files
+            .iter()
+            .map(|entry| (entry.exact_name(), entry))
+            .collect();
+        let mut verification = Verification::default();
+        for actual in &self.files {
+            match unmatched.remove(actual.exact_name()) {
+                Some(expected) if expected.has_same_contents(actual) => {
+                    verification.matched.push(actual.name.clone());
+                }
+                Some(expected) => verification
+                    .mismatched
+                    .push((expected.clone(), actual.clone())),
+                None => verification.unexpected.push(actual.name.clone()),
+            }
+        }
+        verification.missing = unmatched
+            .into_values()
+            .map(|entry| entry.name.clone())
+            .collect();
+        verification
+    }
+}
+
+impl ManifestEntry {
+    /// An entry for the file with the given header name, keeping the
+    /// exact bytes of the name if it isn't valid UTF-8.
+    #[must_use]
+    pub fn new(file_id: u8, file_name: &OsStr, size: u64, packets: usize, sha256: String) -> Self {
+        // Names that aren't UTF-8 only survive parsing on Unix, where
+        // these are the bytes from the header packet.
+        let name_bytes = file_name
+            .to_str()
+            .is_none()
+            .then(|| file_name.as_encoded_bytes().to_vec());
+        Self {
+            file_id,
+            name: file_name.to_string_lossy().into_owned(),
+            name_bytes,
+            size,
+            packets,
+            sha256,
+        }
+    }
+
+    /// The name exactly as it was in the header packet.
+    #[must_use]
+    pub fn exact_name(&self) -> &[u8] {
+        self.name_bytes.as_deref().unwrap_or(self.name.as_bytes())
+    }
+
+    // Hand-written manifests might use uppercase hexadecimal.
+    fn has_same_contents(&self, other: &Self) -> bool {
+        self.size == other.size && self.sha256.eq_ignore_ascii_case(&other.sha256)
+    }
+}
+
+/// How the files we received compare with an expected manifest.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Verification {
+    /// Files with the expected size and hash, in order of file ID.
+    pub matched: Vec<String>,
+    /// The expected entry and the one we got for each file with the
+    /// wrong size or hash, in order of file ID.
+    pub mismatched: Vec<(ManifestEntry, ManifestEntry)>,
+    /// Expected files we didn't receive (in full), in order of name.
+    pub missing: Vec<String>,
+    /// Files we received that weren't expected, in order of file ID.
+    pub unexpected: Vec<String>,
+}
+
+impl Verification {
+    #[must_use]
+    pub const fn is_success(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} file(s) matched the manifest", self.matched.len())?;
+        for (expected, actual) in &self.mismatched {
+            write!(
+                f,
+                "\n  {}: expected {} bytes with sha256 {}, got {} bytes with sha256 {}",
+                expected.name, expected.size, expected.sha256, actual.size, actual.sha256
+            )?;
+        }
+        for name in &self.missing {
+            write!(f, "\n  {name}: missing")?;
+        }
+        for name in &self.unexpected {
+            write!(f, "\n  {name}: not in the manifest")?;
+        }
+        Ok(())
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod manifest_tests {
+    use std::{fs, io};
+
+    use super::{Manifest, ManifestEntry};
+
+    fn manifest() -> Manifest {
+        Manifest {
+            files: vec![ManifestEntry {
+                file_id: 3,
+                name: "small.txt".to_string(),
+                name_bytes: None,
+                size: 3,
+                packets: 1,
+                sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+                    .to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        manifest().save(&path).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap(), manifest());
+
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.contains(r#""name": "small.txt""#));
+        assert!(!json.contains("name_bytes"));
+        assert!(json.ends_with("}\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_arent_utf8_are_kept_exactly() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        let entry = ManifestEntry::new(3, OsStr::from_bytes(b"caf\xe9.txt"), 3, 1, String::new());
+        assert_eq!(entry.name, "caf\u{fffd}.txt");
+        assert_eq!(entry.exact_name(), b"caf\xe9.txt");
+        let manifest = Manifest { files: vec![entry] };
+        manifest.save(&path).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap(), manifest);
+    }
+
+    #[test]
+    fn invalid_manifests_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        fs::write(&path, r#"{"files": [{"file_id": 3}]}"#).unwrap();
+        assert_eq!(
+            Manifest::load(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod verify_tests {
+    use std::fs;
+
+    use super::{Manifest, ManifestEntry, Verification};
+
+    // The SHA-256 hash of "abc".
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    // The SHA-256 hash of "".
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn entry(file_id: u8, name: &str, size: u64, sha256: &str) -> ManifestEntry {
+        ManifestEntry {
+            file_id,
+            name: name.to_string(),
+            name_bytes: None,
+            size,
+            packets: 1,
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn matching_files_verify() {
+        let received = Manifest {
+            files: vec![
+                entry(0, "a.txt", 3, ABC_SHA256),
+                entry(1, "b.txt", 0, EMPTY_SHA256),
+            ],
+        };
+        // IDs and packet counts don't matter, and neither does the case
+        // of the hash.
+        let expected = Manifest {
+            files: vec![
+                entry(0, "b.txt", 0, EMPTY_SHA256),
+                entry(0, "a.txt", 3, &ABC_SHA256.to_uppercase()),
+            ],
+        };
+        let verification = received.verify(&expected);
+        assert!(verification.is_success());
+        assert_eq!(verification.matched, ["a.txt", "b.txt"]);
+        assert_eq!(verification.to_string(), "2 file(s) matched the manifest");
+    }
+
+    #[test]
+    fn discrepancies_are_reported() {
+        let received = Manifest {
+            files: vec![
+                entry(0, "a.txt", 3, ABC_SHA256),
+                entry(1, "b.txt", 3, ABC_SHA256),
+                entry(2, "extra.txt", 0, EMPTY_SHA256),
+            ],
+        };
+        let expected = Manifest {
+            files: vec![
+                entry(0, "a.txt", 3, ABC_SHA256),
+                entry(0, "b.txt", 0, EMPTY_SHA256),
+                entry(0, "c.txt", 3, ABC_SHA256),
+            ],
+        };
+        let verification = received.verify(&expected);
+        assert!(!verification.is_success());
+        assert_eq!(
+            verification,
+            Verification {
+                matched: vec!["a.txt".to_string()],
+                mismatched: vec![(
+                    entry(0, "b.txt", 0, EMPTY_SHA256),
+                    entry(1, "b.txt", 3, ABC_SHA256)
+                )],
+                missing: vec!["c.txt".to_string()],
+                unexpected: vec!["extra.txt".to_string()],
+            }
+        );
+        assert_eq!(
+            verification.to_string(),
+            format!(
+                "1 file(s) matched the manifest\n  \
+                 b.txt: expected 0 bytes with sha256 {EMPTY_SHA256}, \
+                 got 3 bytes with sha256 {ABC_SHA256}\n  \
+                 c.txt: missing\n  \
+                 extra.txt: not in the manifest"
+            )
+        );
+    }
+
+    #[test]
+    fn names_are_matched_exactly() {
+        // Both of these names look like "caf\u{fffd}.txt".
+        let named = |name_bytes: &[u8]| ManifestEntry {
+            name_bytes: Some(name_bytes.to_vec()),
+            ..entry(0, "caf\u{fffd}.txt", 3, ABC_SHA256)
+        };
+        let received = Manifest {
+            files: vec![named(b"caf\xe9.txt")],
+        };
+        let expected = Manifest {
+            files: vec![named(b"caf\xe8.txt")],
+        };
+        let verification = received.verify(&expected);
+        assert_eq!(verification.missing, ["caf\u{fffd}.txt"]);
+        assert_eq!(verification.unexpected, ["caf\u{fffd}.txt"]);
+        assert!(received.verify(&received).is_success());
+    }
+
+    #[test]
+    fn expected_manifests_only_need_names_and_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("expected.json");
+        fs::write(
+            &path,
+            format!(r#"{{"files": [{{"name": "a.txt", "size": 3, "sha256": "{ABC_SHA256}"}}]}}"#),
+        )
+        .unwrap();
+        let expected = Manifest::load(&path).unwrap();
+        let received = Manifest {
+            files: vec![entry(4, "a.txt", 3, ABC_SHA256)],
+        };
+        assert!(received.verify(&expected).is_success());
+    }
+}
@@ -0,0 +1,928 @@
+use std::{
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::file_name::{sanitize_file_name, FileNameError};
+
+/// Everything that can go wrong when writing a reassembled file.
+#[derive(Debug)]
+pub enum WriteError {
+    /// We never received the header packet, so we don't know the
+    /// file's name.
+    MissingFileName,
+    /// We never received the last packet, so we don't know how long
+    /// the file is.
+    UnknownLength,
+    /// These packets (in increasing order) haven't arrived yet.
+    MissingPackets(Vec<u16>),
+    /// The file name isn't safe to write inside the output directory.
+    InvalidFileName(FileNameError),
+    /// The file already exists and the collision policy is `Error`.
+    AlreadyExists(PathBuf),
+    /// One of the directories the file goes in is a symbolic link, which
+    /// we don't follow so that it can't lead outside the root directory,
+    /// or isn't a directory at all.
+    NotADirectory(PathBuf),
+    /// Some of the file has already been written to disk and its
+    /// packets thrown away, so we can't produce it again.
+    AlreadyWritten,
+    /// Writing the file we were streaming to disk failed earlier, so it
+    /// may be missing data and we've given up on it.
+    StreamFailed,
+    Io(io::Error),
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFileName => write!(f, "the header packet never arrived"),
+            Self::UnknownLength => write!(f, "the last packet never arrived"),
+            Self::MissingPackets(packet_numbers) => {
+                write!(f, "{} packet(s) never arrived", packet_numbers.len())
+            }
+            Self::InvalidFileName(e) => write!(f, "unsafe file name: {e}"),
+            Self::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+            Self::NotADirectory(path) => {
+                write!(
+                    f,
+                    "{} is a symbolic link or not a directory",
+                    path.display()
+                )
+            }
+            Self::AlreadyWritten => write!(f, "the file has already been written to disk"),
+            Self::StreamFailed => write!(f, "an earlier write to the file failed"),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl Error for WriteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidFileName(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WriteError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<FileNameError> for WriteError {
+    fn from(e: FileNameError) -> Self {
+        Self::InvalidFileName(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum TemplatePart {
+    Literal(String),
+    FileId,
+    Name,
+}
+
+/// A template for the name we write a file under.
+///
+/// Templates can use the placeholders `{file_id}` and `{name}` (the
+/// file name from the header packet); `{{` and `}}` stand for literal
+/// braces.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NameTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    UnmatchedBrace,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownPlaceholder(name) => write!(
+                f,
+                "unknown placeholder `{{{name}}}` (expected `{{file_id}}` or `{{name}}`)"
+            ),
+            Self::UnmatchedBrace => write!(f, "unmatched `{{` or `}}` in template"),
+        }
+    }
+}
+
+impl Error for TemplateError {}
+
+impl Default for NameTemplate {
+    /// The template `{name}`, i.e., use the name from the header as-is.
+    fn default() -> Self {
+        Self {
+            parts: vec![TemplatePart::Name],
+        }
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let (placeholder, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .ok_or(TemplateError::UnmatchedBrace)?;
+                    let part = match placeholder {
+                        "file_id" => TemplatePart::FileId,
+                        "name" => TemplatePart::Name,
+                        _ => {
+                            return Err(TemplateError::UnknownPlaceholder(placeholder.to_string()))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    chars = rest.chars();
+                }
+                '}' => return Err(TemplateError::UnmatchedBrace),
+                _ => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl NameTemplate {
+    #[must_use]
+    pub fn render(&self, file_id: u8, file_name: &OsStr) -> OsString {
+        let mut rendered = OsString::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => rendered.push(literal),
+                TemplatePart::FileId => rendered.push(file_id.to_string()),
+                TemplatePart::Name => rendered.push(file_name),
+            }
+        }
+        rendered
+    }
+}
+
+/// What to do when the file we want to write already exists.
+///
+/// Two servers often send files with the same name, so this applies to
+/// files written earlier in the same run too; the default keeps both.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum CollisionPolicy {
+    /// Replace the existing file.
+    Overwrite,
+    /// Leave the existing file alone and don't write ours.
+    Skip,
+    /// Write ours under a new name with a numeric suffix, e.g.,
+    /// `small-1.txt`. We give up (with an `AlreadyExists` error) once
+    /// the suffixes up to `MAX_SUFFIX` are all taken.
+    #[default]
+    AddSuffix,
+    /// Fail with an `AlreadyExists` error.
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnknownCollisionPolicy(pub String);
+
+impl Display for UnknownCollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown collision policy `{}` (expected `overwrite`, `skip`, `suffix`, or `error`)",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownCollisionPolicy {}
+
+impl FromStr for CollisionPolicy {
+    type Err = UnknownCollisionPolicy;
+
+    fn from_str(policy: &str) -> Result<Self, UnknownCollisionPolicy> {
+        match policy {
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "suffix" => Ok(Self::AddSuffix),
+            "error" => Ok(Self::Error),
+            _ => Err(UnknownCollisionPolicy(policy.to_string())),
+        }
+    }
+}
+
+/// The highest numeric suffix `CollisionPolicy::AddSuffix` tries.
+pub const MAX_SUFFIX: u32 = 1000;
+
+/// Files are written under a temporary name ending in this suffix and
+/// then renamed into place, so an interrupted write never leaves a
+/// truncated file under the real name.
+pub const TEMP_FILE_SUFFIX: &str = ".segmented-tmp";
+
+/// Where and how downloaded files get written.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct OutputConfig {
+    pub(crate) root: PathBuf,
+    pub(crate) template: NameTemplate,
+    pub(crate) on_collision: CollisionPolicy,
+    pub(crate) sync: bool,
+}
+
+impl Default for OutputConfig {
+    /// Write files into the current directory under the names from
+    /// their headers, adding a numeric suffix to the name if a file
+    /// already exists.
+    fn default() -> Self {
+        Self::new(".")
+    }
+}
+
+impl OutputConfig {
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            template: NameTemplate::default(),
+            on_collision: CollisionPolicy::default(),
+            sync: false,
+        }
+    }
+
+    #[must_use]
+    pub fn with_template(mut self, template: NameTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_collision_policy(mut self, on_collision: CollisionPolicy) -> Self {
+        self.on_collision = on_collision;
+        self
+    }
+
+    /// Whether to `fsync` each file (and its directory) before and
+    /// after renaming it into place, so that it survives a power
+    /// failure. This makes writing noticeably slower.
+    #[must_use]
+    pub const fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Create a temporary file to write the file with the given ID and
+    /// header name to, returning `None` if the collision policy says
+    /// to skip it. Nothing appears under the real name until the file
+    /// is committed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the rendered name isn't safe to write
+    /// inside the root directory, if one of the directories the file
+    /// goes in is a symbolic link, if the file exists and the collision
+    /// policy is `Error` (or `AddSuffix` and every suffix is taken), or
+    /// if we couldn't create the temporary file.
+    pub(crate) fn create_file(
+        &self,
+        file_id: u8,
+        file_name: &OsStr,
+    ) -> Result<Option<OutputFile>, WriteError> {
+        let relative_path = sanitize_file_name(&self.template.render(file_id, file_name))?;
+        let parent = create_directories(&self.root, relative_path.parent())?;
+        let mut path = self.root.join(relative_path);
+
+        // This is only a first check so we don't download a file for
+        // nothing; `OutputFile::commit` makes sure we don't clobber a
+        // file that shows up in the meantime.
+        if fs::symlink_metadata(&path).is_ok() {
+            match self.on_collision {
+                CollisionPolicy::Overwrite => {}
+                CollisionPolicy::Skip => return Ok(None),
+                CollisionPolicy::Error => return Err(WriteError::AlreadyExists(path)),
+                CollisionPolicy::AddSuffix => path = first_free_suffix(&path)?,
+            }
+        }
+
+        let temp_path = parent.join(temp_file_name());
+        let file = File::create(&temp_path)?;
+        Ok(Some(OutputFile {
+            path,
+            temp_path,
+            file,
+            on_collision: self.on_collision,
+            sync: self.sync,
+        }))
+    }
+
+    /// Remove temporary files left behind under the root directory by
+    /// an earlier run that was interrupted, returning their paths. Only
+    /// call this when no other client is writing to the same directory.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we couldn't read a directory or remove a
+    /// file. A missing root directory isn't an error.
+    pub fn remove_stale_temp_files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        let mut directories = vec![self.root.clone()];
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?;
+                // This doesn't follow symbolic links, so we can't wander
+                // outside of the root directory.
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    directories.push(entry.path());
+                } else if file_type.is_file() && is_temp_file_name(&entry.file_name()) {
+                    fs::remove_file(entry.path())?;
+                    removed.push(entry.path());
+                }
+            }
+        }
+        removed.sort();
+        Ok(removed)
+    }
+}
+
+// A name that no other temporary file (in this process or any other
+// running one) has.
+pub(crate) fn temp_file_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!(".{}-{count}{TEMP_FILE_SUFFIX}", process::id())
+}
+
+// Create the directories in `relative_path` (if any) under `root`, one
+// at a time, returning the last one. We refuse to go through a symbolic
+// link, so one planted under the root can't send files elsewhere; the
+// root itself is up to the user.
+fn create_directories(root: &Path, relative_path: Option<&Path>) -> Result<PathBuf, WriteError> {
+    fs::create_dir_all(root)?;
+    let mut directory = root.to_path_buf();
+    for component in relative_path.into_iter().flat_map(Path::components) {
+        directory.push(component);
+        match fs::create_dir(&directory) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+        if !fs::symlink_metadata(&directory)?.is_dir() {
+            return Err(WriteError::NotADirectory(directory));
+        }
+    }
+    Ok(directory)
+}
+
+fn is_temp_file_name(file_name: &OsStr) -> bool {
+    let file_name = file_name.as_encoded_bytes();
+    file_name.starts_with(b".") && file_name.ends_with(TEMP_FILE_SUFFIX.as_bytes())
+}
+
+// Fails with `AlreadyExists` if every suffix up to `MAX_SUFFIX` is
+// taken.
+fn first_free_suffix(path: &Path) -> Result<PathBuf, WriteError> {
+    (1..=MAX_SUFFIX)
+        .map(|suffix| with_numeric_suffix(path, suffix))
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .ok_or_else(|| WriteError::AlreadyExists(path.to_path_buf()))
+}
+
+/// A file being written under a temporary name in its destination
+/// directory. `commit` moves it into place; dropping it without
+/// committing removes the temporary file.
+#[derive(Debug)]
+pub(crate) struct OutputFile {
+    // Where we intend to put the file. With `AddSuffix` we may end up
+    // picking another name if this one is taken by the time we commit.
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+    on_collision: CollisionPolicy,
+    sync: bool,
+}
+
+impl OutputFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // What we've written so far, read back from the temporary file.
+    pub(crate) fn read_back(&self) -> io::Result<Vec<u8>> {
+        fs::read(&self.temp_path)
+    }
+
+    /// Move the file into place, returning where it ended up, or `None`
+    /// if the collision policy is `Skip` and a file with the same name
+    /// appeared while we were writing.
+    ///
+    /// Renaming within a directory is atomic, so readers either see no
+    /// file or the complete file. The policies that mustn't replace an
+    /// existing file use a hard link instead, which fails rather than
+    /// replacing anything.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the policy is `Error` and the file now
+    /// exists (or `AddSuffix` and every suffix is now taken), or if
+    /// syncing, renaming, or linking fails. The temporary file is kept
+    /// until `self` is dropped, so we can try again.
+    pub(crate) fn commit(&self) -> Result<Option<PathBuf>, WriteError> {
+        if self.sync {
+            self.file.sync_all()?;
+        }
+        let path = match self.on_collision {
+            CollisionPolicy::Overwrite => {
+                fs::rename(&self.temp_path, &self.path)?;
+                Some(self.path.clone())
+            }
+            CollisionPolicy::Skip => match fs::hard_link(&self.temp_path, &self.path) {
+                Ok(()) => Some(self.path.clone()),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => None,
+                Err(e) => return Err(e.into()),
+            },
+            CollisionPolicy::Error => match fs::hard_link(&self.temp_path, &self.path) {
+                Ok(()) => Some(self.path.clone()),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Err(WriteError::AlreadyExists(self.path.clone()))
+                }
+                Err(e) => return Err(e.into()),
+            },
+            CollisionPolicy::AddSuffix => {
+                let mut candidate = self.path.clone();
+                loop {
+                    match fs::hard_link(&self.temp_path, &candidate) {
+                        Ok(()) => break Some(candidate),
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                            candidate = first_free_suffix(&self.path)?;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        };
+        // Dropping the file removes the temporary name (if we linked
+        // rather than renamed).
+        if self.sync {
+            sync_directory(&self.temp_path)?;
+        }
+        Ok(path)
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+impl OutputFile {
+    // Make every later write fail, as if the disk had filled up.
+    pub(crate) fn fail_writes(&mut self) -> io::Result<()> {
+        self.file = File::open(&self.temp_path)?;
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        // This fails if we renamed the file into place, which is fine.
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+// Make a rename or link in the directory containing `path` durable.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(directory) => File::open(directory)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// Windows doesn't let us open (and so sync) a directory.
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// Turn `dir/small.txt` into `dir/small-<suffix>.txt`.
+fn with_numeric_suffix(path: &Path, suffix: u32) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-{suffix}"));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod template_tests {
+    use std::ffi::{OsStr, OsString};
+
+    use super::{NameTemplate, TemplateError};
+
+    fn render(template: &str) -> Result<OsString, TemplateError> {
+        let template: NameTemplate = template.parse()?;
+        Ok(template.render(7, OsStr::new("small.txt")))
+    }
+
+    #[test]
+    fn default_is_just_the_name() {
+        assert_eq!(
+            NameTemplate::default().render(7, OsStr::new("small.txt")),
+            "small.txt"
+        );
+    }
+
+    #[test]
+    fn file_id_and_name() {
+        assert_eq!(render("{file_id}-{name}"), Ok("7-small.txt".into()));
+    }
+
+    #[test]
+    fn literal_text_and_escaped_braces() {
+        assert_eq!(
+            render("out/{{{file_id}}}_{name}.bak"),
+            Ok("out/{7}_small.txt.bak".into())
+        );
+    }
+
+    #[test]
+    fn unknown_placeholder() {
+        assert_eq!(
+            render("{id}-{name}"),
+            Err(TemplateError::UnknownPlaceholder("id".to_string()))
+        );
+    }
+
+    #[test]
+    fn unmatched_braces() {
+        assert_eq!(render("{name"), Err(TemplateError::UnmatchedBrace));
+        assert_eq!(render("name}"), Err(TemplateError::UnmatchedBrace));
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod create_file_tests {
+    use std::{ffi::OsStr, fs, io::Write};
+
+    use crate::file_name::FileNameError;
+
+    use super::{CollisionPolicy, OutputConfig, WriteError, MAX_SUFFIX};
+
+    fn config(root: &std::path::Path, on_collision: CollisionPolicy) -> OutputConfig {
+        OutputConfig::new(root).with_collision_policy(on_collision)
+    }
+
+    #[test]
+    fn uses_template() {
+        let root = tempfile::tempdir().unwrap();
+        let config =
+            OutputConfig::new(root.path()).with_template("{file_id}-{name}".parse().unwrap());
+        let path = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap()
+            .commit()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.path().join("3-small.txt"), path);
+    }
+
+    #[test]
+    fn rejects_unsafe_rendered_name() {
+        let root = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(root.path()).with_template("../{name}".parse().unwrap());
+        let error = config.create_file(3, OsStr::new("small.txt")).unwrap_err();
+        assert!(matches!(
+            error,
+            WriteError::InvalidFileName(FileNameError::ParentDirectory)
+        ));
+    }
+
+    #[test]
+    fn creates_subdirectories() {
+        let root = tempfile::tempdir().unwrap();
+        let path = OutputConfig::new(root.path())
+            .create_file(3, OsStr::new("docs/notes/small.txt"))
+            .unwrap()
+            .unwrap()
+            .commit()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            root.path().join("docs").join("notes").join("small.txt"),
+            path
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinked_directories() {
+        let root = tempfile::tempdir().unwrap();
+        let elsewhere = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(elsewhere.path(), root.path().join("docs")).unwrap();
+        fs::write(root.path().join("notes"), "not a directory").unwrap();
+        let config = OutputConfig::new(root.path());
+        for (file_name, directory) in [("docs/small.txt", "docs"), ("notes/small.txt", "notes")] {
+            let error = config.create_file(3, OsStr::new(file_name)).unwrap_err();
+            assert!(
+                matches!(error, WriteError::NotADirectory(path) if path == root.path().join(directory))
+            );
+        }
+        assert_eq!(fs::read_dir(elsewhere.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn overwrite() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("small.txt"), "old").unwrap();
+        let config = config(root.path(), CollisionPolicy::Overwrite);
+        let path = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap()
+            .commit()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.path().join("small.txt"), path);
+        assert_eq!("", fs::read_to_string(path).unwrap());
+    }
+
+    #[test]
+    fn skip() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("small.txt"), "old").unwrap();
+        let config = config(root.path(), CollisionPolicy::Skip);
+        assert!(config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            "old",
+            fs::read_to_string(root.path().join("small.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn add_suffix() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("small.txt"), "old").unwrap();
+        fs::write(root.path().join("small-1.txt"), "old").unwrap();
+        let config = config(root.path(), CollisionPolicy::AddSuffix);
+        let path = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap()
+            .commit()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.path().join("small-2.txt"), path);
+    }
+
+    #[test]
+    fn run_out_of_suffixes() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("small.txt");
+        fs::write(&path, "old").unwrap();
+        for suffix in 1..MAX_SUFFIX {
+            fs::write(root.path().join(format!("small-{suffix}.txt")), "old").unwrap();
+        }
+        let config = config(root.path(), CollisionPolicy::AddSuffix);
+        let file = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            file.commit().unwrap(),
+            Some(root.path().join(format!("small-{MAX_SUFFIX}.txt")))
+        );
+        let error = config.create_file(3, OsStr::new("small.txt")).unwrap_err();
+        assert!(
+            matches!(error, WriteError::AlreadyExists(already_exists) if already_exists == path)
+        );
+    }
+
+    #[test]
+    fn files_from_the_same_run_get_a_suffix_by_default() {
+        let root = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(root.path());
+        let mut paths = Vec::new();
+        for contents in ["first", "second"] {
+            let mut file = config
+                .create_file(3, OsStr::new("small.txt"))
+                .unwrap()
+                .unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+            paths.push(file.commit().unwrap().unwrap());
+        }
+        assert_eq!(
+            paths,
+            vec![
+                root.path().join("small.txt"),
+                root.path().join("small-1.txt")
+            ]
+        );
+        assert_eq!(fs::read_to_string(&paths[0]).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&paths[1]).unwrap(), "second");
+    }
+
+    #[test]
+    fn add_suffix_without_extension() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("README"), "old").unwrap();
+        let config = config(root.path(), CollisionPolicy::AddSuffix);
+        let path = config
+            .create_file(3, OsStr::new("README"))
+            .unwrap()
+            .unwrap()
+            .commit()
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.path().join("README-1"), path);
+    }
+
+    #[test]
+    fn error() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("small.txt"), "old").unwrap();
+        let config = config(root.path(), CollisionPolicy::Error);
+        let error = config.create_file(3, OsStr::new("small.txt")).unwrap_err();
+        assert!(
+            matches!(error, WriteError::AlreadyExists(path) if path == root.path().join("small.txt"))
+        );
+    }
+
+    #[test]
+    fn parse_collision_policy() {
+        assert_eq!(Ok(CollisionPolicy::AddSuffix), "suffix".parse());
+        assert!("rename".parse::<CollisionPolicy>().is_err());
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod atomic_write_tests {
+    use std::{ffi::OsStr, fs, io::Write, path::Path};
+
+    use super::{CollisionPolicy, OutputConfig, WriteError, TEMP_FILE_SUFFIX};
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn nothing_appears_until_commit() {
+        let root = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(root.path()).with_sync(true);
+        let mut file = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap();
+        file.write_all(b"hello").unwrap();
+        let names = file_names(root.path());
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with('.') && names[0].ends_with(TEMP_FILE_SUFFIX));
+
+        let path = file.commit().unwrap().unwrap();
+        drop(file);
+        assert_eq!(path, root.path().join("small.txt"));
+        assert_eq!(file_names(root.path()), vec!["small.txt"]);
+        assert_eq!(fs::read(path).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn dropping_without_commit_removes_the_temporary_file() {
+        let root = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(root.path());
+        let mut file = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap();
+        file.write_all(b"hel").unwrap();
+        drop(file);
+        assert!(file_names(root.path()).is_empty());
+    }
+
+    #[test]
+    fn commit_does_not_clobber_a_file_that_appeared_meanwhile() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("small.txt");
+        for (on_collision, expected) in [
+            (CollisionPolicy::Skip, None),
+            (
+                CollisionPolicy::AddSuffix,
+                Some(root.path().join("small-1.txt")),
+            ),
+        ] {
+            let config = OutputConfig::new(root.path()).with_collision_policy(on_collision);
+            let file = config
+                .create_file(3, OsStr::new("small.txt"))
+                .unwrap()
+                .unwrap();
+            fs::write(&path, "theirs").unwrap();
+            assert_eq!(file.commit().unwrap(), expected);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "theirs");
+            fs::remove_file(&path).unwrap();
+        }
+
+        let config = OutputConfig::new(root.path()).with_collision_policy(CollisionPolicy::Error);
+        let file = config
+            .create_file(3, OsStr::new("small.txt"))
+            .unwrap()
+            .unwrap();
+        fs::write(&path, "theirs").unwrap();
+        assert!(matches!(file.commit(), Err(WriteError::AlreadyExists(_))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "theirs");
+        // We keep the temporary file so that we can try again.
+        assert_eq!(file_names(root.path()).len(), 3);
+        drop(file);
+        assert_eq!(file_names(root.path()), vec!["small-1.txt", "small.txt"]);
+    }
+
+    #[test]
+    fn removes_stale_temporary_files() {
+        let root = tempfile::tempdir().unwrap();
+        let subdirectory = root.path().join("docs");
+        fs::create_dir(&subdirectory).unwrap();
+        let stale = [
+            root.path().join(format!(".123-0{TEMP_FILE_SUFFIX}")),
+            subdirectory.join(format!(".456-7{TEMP_FILE_SUFFIX}")),
+        ];
+        for path in &stale {
+            fs::write(path, "partial").unwrap();
+        }
+        fs::write(root.path().join("small.txt"), "keep").unwrap();
+        fs::write(
+            root.path().join(format!("not-hidden{TEMP_FILE_SUFFIX}")),
+            "keep",
+        )
+        .unwrap();
+
+        let config = OutputConfig::new(root.path());
+        let mut removed = config.remove_stale_temp_files().unwrap();
+        removed.sort();
+        let mut expected = stale.to_vec();
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert_eq!(file_names(&subdirectory), Vec::<String>::new());
+        assert_eq!(file_names(root.path()).len(), 3);
+    }
+
+    #[test]
+    fn missing_root_has_no_stale_files() {
+        let root = tempfile::tempdir().unwrap();
+        let config = OutputConfig::new(root.path().join("missing"));
+        assert!(config.remove_stale_temp_files().unwrap().is_empty());
+    }
+}
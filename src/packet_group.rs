@@ -1,71 +1,2098 @@
 use std::{
-    collections::HashMap,
-    ffi::OsString,
-    fs::File,
-    io::{self, Write},
+    borrow::Cow,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    io::{self, Read, Write},
+    iter::Take,
+    mem,
+    ops::{AddAssign, RangeInclusive},
+    path::PathBuf,
+    str::FromStr,
 };
 
-use crate::packets::{Data, Header, Packet};
+use sha2::{Digest, Sha256};
 
-#[derive(Default, Debug, PartialEq, Eq)]
+use crate::{
+    output::{OutputConfig, OutputFile, WriteError},
+    packet_store::PacketStore,
+    packets::{Packet, PacketRef},
+};
+
+/// What a `PacketGroup` is still waiting for.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GapReport {
+    pub header_missing: bool,
+    /// `None` until the last packet arrives.
+    pub last_packet_number: Option<u16>,
+    /// The missing packet numbers as ranges, in increasing order. Until
+    /// the last packet arrives we can only report the gaps below the
+    /// highest packet number we've seen.
+    pub missing: Vec<RangeInclusive<u16>>,
+}
+
+impl GapReport {
+    #[must_use]
+    pub const fn is_complete(&self) -> bool {
+        !self.header_missing && self.last_packet_number.is_some() && self.missing.is_empty()
+    }
+
+    /// Every missing packet number, in increasing order.
+    pub fn missing_packet_numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        self.missing.iter().flat_map(Clone::clone)
+    }
+}
+
+impl Display for GapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_complete() {
+            return write!(f, "complete");
+        }
+        let mut problems = Vec::new();
+        if self.header_missing {
+            problems.push("header missing".to_string());
+        }
+        if self.last_packet_number.is_none() {
+            problems.push("last packet missing".to_string());
+        }
+        if !self.missing.is_empty() {
+            let ranges: Vec<String> = self
+                .missing
+                .iter()
+                .map(|range| {
+                    if range.start() == range.end() {
+                        range.start().to_string()
+                    } else {
+                        format!("{}-{}", range.start(), range.end())
+                    }
+                })
+                .collect();
+            problems.push(format!("missing packets {}", ranges.join(", ")));
+        }
+        write!(f, "{}", problems.join("; "))
+    }
+}
+
+/// How far along the download of a single file is.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileProgress {
+    /// `None` until the header packet arrives.
+    pub file_name: Option<OsString>,
+    pub packets_received: usize,
+    /// `None` until the last packet arrives.
+    pub expected_packets: Option<usize>,
+    pub bytes_received: usize,
+}
+
+impl FileProgress {
+    /// The percentage of packets we've received, or `None` if we
+    /// don't know how many packets there are yet.
+    #[must_use]
+    pub fn percentage(&self) -> Option<f64> {
+        self.expected_packets
+            .map(|expected_packets| percentage(self.packets_received, expected_packets))
+    }
+}
+
+#[expect(
+    clippy::cast_precision_loss,
+    reason = "packet counts are far smaller than 2^52"
+)]
+pub(crate) fn percentage(received: usize, expected: usize) -> f64 {
+    if expected == 0 {
+        return 100.0;
+    }
+    (received as f64 / expected as f64 * 100.0).min(100.0)
+}
+
+/// What a `PacketGroup` did with a packet.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PacketOutcome {
+    /// We hadn't seen this packet before.
+    New,
+    /// We'd already seen this exact packet.
+    Duplicate,
+    /// We'd already seen a packet with this number but different
+    /// contents; the `ConflictPolicy` decided which one we kept.
+    Conflict,
+    /// A header with a different file name arrived and the
+    /// `HeaderChangePolicy` is `Restart`, so we threw away everything
+    /// we had for this file ID and started a fresh transfer.
+    Restarted,
+    /// A new packet that was the first to say it's the last one, so we
+    /// dropped the packets we were holding with higher numbers (listed
+    /// here) because they turned out to be strays. It counts as new;
+    /// the dropped packets count as rejected.
+    DroppedLaterPackets(Vec<u16>),
+}
+
+/// Which packet to keep when two packets with the same number have
+/// different contents.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum ConflictPolicy {
+    /// Keep the packet that arrived first.
+    #[default]
+    KeepFirst,
+    /// Replace the earlier packet with the one that arrived last.
+    KeepLast,
+    /// Keep the packet that arrived first and report a
+    /// `ProcessError::ConflictingPacket`.
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnknownConflictPolicy(pub String);
+
+impl Display for UnknownConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown conflict policy `{}` (expected `keep-first`, `keep-last`, or `error`)",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownConflictPolicy {}
+
+impl FromStr for ConflictPolicy {
+    type Err = UnknownConflictPolicy;
+
+    fn from_str(policy: &str) -> Result<Self, UnknownConflictPolicy> {
+        match policy {
+            "keep-first" => Ok(Self::KeepFirst),
+            "keep-last" => Ok(Self::KeepLast),
+            "error" => Ok(Self::Error),
+            _ => Err(UnknownConflictPolicy(policy.to_string())),
+        }
+    }
+}
+
+/// What to do when a header arrives with a different file name than
+/// the header we already have for that file ID, e.g., because the
+/// server restarted and reused the ID for another file.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum HeaderChangePolicy {
+    /// Keep the original name and report a
+    /// `ProcessError::FileNameChanged`.
+    #[default]
+    Reject,
+    /// Throw away everything we have for the file ID and start a new
+    /// transfer under the new name.
+    Restart,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UnknownHeaderChangePolicy(pub String);
+
+impl Display for UnknownHeaderChangePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown header change policy `{}` (expected `reject` or `restart`)",
+            self.0
+        )
+    }
+}
+
+impl Error for UnknownHeaderChangePolicy {}
+
+impl FromStr for HeaderChangePolicy {
+    type Err = UnknownHeaderChangePolicy;
+
+    fn from_str(policy: &str) -> Result<Self, UnknownHeaderChangePolicy> {
+        match policy {
+            "reject" => Ok(Self::Reject),
+            "restart" => Ok(Self::Restart),
+            _ => Err(UnknownHeaderChangePolicy(policy.to_string())),
+        }
+    }
+}
+
+/// A packet that is inconsistent with the packets we've already seen.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ProcessError {
+    /// A packet with this number arrived with different contents, and
+    /// the conflict policy is `Error`.
+    ConflictingPacket { packet_number: u16 },
+    /// A packet arrived with a number after the last packet's number.
+    /// If it arrived first it's dropped when the last packet arrives
+    /// instead (see `PacketOutcome::DroppedLaterPackets`).
+    BeyondLastPacket {
+        packet_number: u16,
+        last_packet_number: u16,
+    },
+    /// The last packet arrived, but we'd already written packets after
+    /// it to disk and can't take them back. It is rejected.
+    LastPacketBeforeWritten {
+        last_packet_number: u16,
+        packets_written: usize,
+    },
+    /// A second packet claimed to be the last packet. It is rejected
+    /// and we keep the first one.
+    ConflictingLastPacket {
+        last_packet_number: u16,
+        packet_number: u16,
+    },
+    /// A header arrived with a different file name and the header
+    /// change policy is `Reject`. We keep the old name.
+    FileNameChanged {
+        old_name: OsString,
+        new_name: OsString,
+    },
+    /// The packet store couldn't save or read back a packet (see
+    /// `PacketStore`). The packet isn't stored.
+    StoreFailed { packet_number: u16, message: String },
+}
+
+impl ProcessError {
+    fn store_failed(packet_number: u16, error: &io::Error) -> Self {
+        Self::StoreFailed {
+            packet_number,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ConflictingPacket { packet_number } => write!(
+                f,
+                "packet {packet_number} arrived twice with different contents"
+            ),
+            Self::BeyondLastPacket {
+                packet_number,
+                last_packet_number,
+            } => write!(
+                f,
+                "packet {packet_number} comes after the last packet ({last_packet_number})"
+            ),
+            Self::LastPacketBeforeWritten {
+                last_packet_number,
+                packets_written,
+            } => write!(
+                f,
+                "packet {last_packet_number} claims to be the last packet, but we've \
+                 already written {packets_written} packets to disk"
+            ),
+            Self::ConflictingLastPacket {
+                last_packet_number,
+                packet_number,
+            } => write!(
+                f,
+                "packet {packet_number} claims to be the last packet, but packet \
+                 {last_packet_number} already did"
+            ),
+            Self::FileNameChanged { old_name, new_name } => write!(
+                f,
+                "the file name changed from {} to {}",
+                old_name.display(),
+                new_name.display()
+            ),
+            Self::StoreFailed {
+                packet_number,
+                message,
+            } => write!(f, "couldn't store packet {packet_number}: {message}"),
+        }
+    }
+}
+
+impl Error for ProcessError {}
+
+/// How many packets of each kind a `PacketGroup` has seen.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct PacketStats {
+    pub new: usize,
+    pub duplicates: usize,
+    pub conflicts: usize,
+    /// Packets that were inconsistent with the last packet number, and
+    /// headers that tried to change the file name.
+    pub rejected: usize,
+    pub restarts: usize,
+}
+
+impl PacketStats {
+    const fn record(&mut self, outcome: &PacketOutcome) {
+        match outcome {
+            PacketOutcome::New | PacketOutcome::DroppedLaterPackets(_) => self.new += 1,
+            PacketOutcome::Duplicate => self.duplicates += 1,
+            PacketOutcome::Conflict => self.conflicts += 1,
+            PacketOutcome::Restarted => self.restarts += 1,
+        }
+    }
+}
+
+impl Display for PacketStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new, {} duplicate, {} conflicting, and {} rejected packet(s), \
+             {} restart(s)",
+            self.new, self.duplicates, self.conflicts, self.rejected, self.restarts
+        )
+    }
+}
+
+impl AddAssign for PacketStats {
+    fn add_assign(&mut self, other: Self) {
+        self.new += other.new;
+        self.duplicates += other.duplicates;
+        self.conflicts += other.conflicts;
+        self.rejected += other.rejected;
+        self.restarts += other.restarts;
+    }
+}
+
+/// The size and SHA-256 hash of a reassembled file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FileDigest {
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl FileDigest {
+    /// The SHA-256 hash as lowercase hexadecimal.
+    #[must_use]
+    pub fn sha256_hex(&self) -> String {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        let mut hex = String::with_capacity(2 * self.sha256.len());
+        for byte in self.sha256 {
+            hex.push(char::from(DIGITS[usize::from(byte >> 4)]));
+            hex.push(char::from(DIGITS[usize::from(byte & 0xf)]));
+        }
+        hex
+    }
+}
+
+impl Display for FileDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes, sha256 {}", self.size, self.sha256_hex())
+    }
+}
+
+// Hashes a file a packet at a time.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct DigestBuilder {
+    sha256: Sha256,
+    size: u64,
+}
+
+impl DigestBuilder {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        self.size += data.len() as u64;
+    }
+
+    fn finish(self) -> FileDigest {
+        FileDigest {
+            size: self.size,
+            sha256: self.sha256.finalize().into(),
+        }
+    }
+}
+
+/// Reads a reassembled file straight out of the packets in a
+/// `PacketGroup`; see `PacketGroup::reader`.
+#[derive(Debug)]
+pub struct FileReader<'a> {
+    packets: &'a dyn PacketStore,
+    // The packets we haven't started reading yet, in order.
+    packet_numbers: Take<RangeInclusive<u16>>,
+    // The packet we're reading and how much of it we've read.
+    current: Cow<'a, [u8]>,
+    position: usize,
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            let Some(packet_number) = self.packet_numbers.next() else {
+                return Ok(0);
+            };
+            self.current = self.packets.get(packet_number)?.unwrap_or_default();
+            self.position = 0;
+        }
+        let read = (&self.current[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// Where a `PacketGroup` that's streaming its file to disk is writing.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Writing(OutputFile),
+    // The file already existed and the collision policy is `Skip`, so
+    // we just throw the packets away.
+    Skipped,
+    // We've written the whole file and moved it into place (or skipped
+    // it, if the path is `None`), and thrown away all of its packets.
+    Finished(Option<PathBuf>),
+    // A write failed, possibly after writing part of a packet, so the
+    // file can't be trusted. Its temporary file is gone and we won't
+    // try again.
+    Failed,
+    // We were writing the file when a checkpoint was saved, and these
+    // are the bytes we'd written. They go to a new temporary file the
+    // next time we write (see `checkpoint`).
+    Restored(Vec<u8>),
+}
+
+impl PartialEq for Stream {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Writing(file), Self::Writing(other_file)) => file.path() == other_file.path(),
+            (Self::Skipped, Self::Skipped) | (Self::Failed, Self::Failed) => true,
+            (Self::Finished(path), Self::Finished(other_path)) => path == other_path,
+            (Self::Restored(bytes), Self::Restored(other_bytes)) => bytes == other_bytes,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Stream {}
+
+#[derive(Default, Debug)]
 pub struct PacketGroup {
     pub(crate) file_name: Option<OsString>,
     pub(crate) expected_number_of_packets: Option<usize>,
-    pub(crate) packets: HashMap<u16, Vec<u8>>,
+    // The packets we've received but haven't written yet.
+    pub(crate) packets: Box<dyn PacketStore>,
+    // When streaming, packets `0..packets_written` have already been
+    // written to disk and dropped from `packets`.
+    pub(crate) packets_written: usize,
+    pub(crate) stream: Option<Stream>,
+    // The total length of every packet we've received, including the
+    // ones we've already written.
+    pub(crate) bytes_received: usize,
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) header_change_policy: HeaderChangePolicy,
+    pub(crate) stats: PacketStats,
+    // The hash of packets `0..packets_written`, which we may have
+    // already thrown away.
+    pub(crate) prefix_digest: DigestBuilder,
+    // Set once we've hashed the whole file.
+    pub(crate) digest: Option<FileDigest>,
+}
+
+// Written by hand because deriving it doesn't work for the boxed store.
+impl PartialEq for PacketGroup {
+    fn eq(&self, other: &Self) -> bool {
+        self.file_name == other.file_name
+            && self.expected_number_of_packets == other.expected_number_of_packets
+            && *self.packets == *other.packets
+            && self.packets_written == other.packets_written
+            && self.stream == other.stream
+            && self.bytes_received == other.bytes_received
+            && self.conflict_policy == other.conflict_policy
+            && self.header_change_policy == other.header_change_policy
+            && self.stats == other.stats
+            // `prefix_digest` can't be compared, but it only depends on
+            // the packets we've already written.
+            && self.digest == other.digest
+    }
 }
 
+impl Eq for PacketGroup {}
+
 impl PacketGroup {
+    #[must_use]
+    pub const fn with_conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_header_change_policy(
+        mut self,
+        header_change_policy: HeaderChangePolicy,
+    ) -> Self {
+        self.header_change_policy = header_change_policy;
+        self
+    }
+
+    /// Keep the packets in `store` instead of in memory. Call this
+    /// before processing any packets; the old store and anything in it
+    /// are dropped.
+    #[must_use]
+    pub fn with_store(mut self, packets: Box<dyn PacketStore>) -> Self {
+        self.packets = packets;
+        self
+    }
+
+    // Move every packet we're holding into `store`, which is used from
+    // now on.
+    pub(crate) fn move_to_store(&mut self, mut store: Box<dyn PacketStore>) -> io::Result<()> {
+        for packet_number in self.packets.packet_numbers() {
+            if let Some(data) = self.packets.get(packet_number)? {
+                store.insert(packet_number, &data)?;
+            }
+        }
+        self.packets = store;
+        Ok(())
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> PacketStats {
+        self.stats
+    }
+
+    /// The file name from the header packet, or `None` until it
+    /// arrives.
+    #[must_use]
+    pub fn file_name(&self) -> Option<&OsStr> {
+        self.file_name.as_deref()
+    }
+
+    /// How many packets the file has, or `None` until the last packet
+    /// arrives.
+    #[must_use]
+    pub const fn expected_number_of_packets(&self) -> Option<usize> {
+        self.expected_number_of_packets
+    }
+
+    /// How many different data packets we've received, including any
+    /// we've already written to disk.
+    #[must_use]
+    pub fn packets_received(&self) -> usize {
+        self.packets_written + self.packets.len()
+    }
+
+    /// How many packets (from the start of the file) we've written to
+    /// disk and no longer hold.
+    #[must_use]
+    pub const fn packets_written(&self) -> usize {
+        self.packets_written
+    }
+
+    /// The total length of the data packets we've received.
+    #[must_use]
+    pub const fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    #[must_use]
+    pub const fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict_policy
+    }
+
+    #[must_use]
+    pub const fn header_change_policy(&self) -> HeaderChangePolicy {
+        self.header_change_policy
+    }
+
     #[must_use]
     pub fn received_all_packets(&self) -> bool {
-        self.expected_number_of_packets == Some(self.packets.len())
+        self.expected_number_of_packets == Some(self.packets_received())
         // self.expected_number_of_packets.map(|expected_number| {
         //     expected_number == self.packets.len()
         // }).unwrap_or(false)
     }
 
-    pub fn process_packet(&mut self, packet: Packet) {
-        match packet {
-            Packet::Header(header) => self.process_header_packet(header),
-            Packet::Data(data) => self.process_data_packet(data),
+    /// Whether we have the header and every data packet.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        !self.header_missing() && self.received_all_packets()
+    }
+
+    #[must_use]
+    pub const fn header_missing(&self) -> bool {
+        self.file_name.is_none()
+    }
+
+    // `None` until the last packet arrives.
+    fn last_packet_number(&self) -> Option<u16> {
+        self.expected_number_of_packets
+            .and_then(|expected_number_of_packets| expected_number_of_packets.checked_sub(1))
+            .and_then(|last_packet_number| u16::try_from(last_packet_number).ok())
+    }
+
+    fn highest_received_packet_number(&self) -> Option<u16> {
+        self.packets.highest_packet_number().or_else(|| {
+            self.packets_written
+                .checked_sub(1)
+                .and_then(|packet_number| u16::try_from(packet_number).ok())
+        })
+    }
+
+    // The highest packet number we know we need: the number of the
+    // last packet if it has arrived, otherwise the highest number
+    // we've seen so far.
+    fn highest_needed_packet_number(&self) -> Option<u16> {
+        self.last_packet_number()
+            .or_else(|| self.highest_received_packet_number())
+    }
+
+    /// The missing packet numbers as ranges, in increasing order. Until
+    /// the last packet arrives this only includes the gaps below the
+    /// highest packet number we've seen.
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        let Some(highest_needed) = self.highest_needed_packet_number() else {
+            return Vec::new();
+        };
+        let received = self
+            .packets
+            .packet_numbers()
+            .into_iter()
+            .take_while(|&packet_number| packet_number <= highest_needed);
+
+        let mut missing = Vec::new();
+        // The first packet number we haven't accounted for yet; this is
+        // `None` once we've accounted for `highest_needed`. Everything
+        // we've already written has arrived.
+        let mut next = u16::try_from(self.packets_written).ok();
+        for packet_number in received {
+            if let Some(first_missing) = next.filter(|&next| next < packet_number) {
+                missing.push(first_missing..=packet_number - 1);
+            }
+            next = packet_number.checked_add(1);
         }
+        if let Some(first_missing) = next.filter(|&next| next <= highest_needed) {
+            missing.push(first_missing..=highest_needed);
+        }
+        missing
     }
 
-    fn process_header_packet(&mut self, header: Header) {
-        self.file_name = Some(header.file_name);
+    /// Every missing packet number, in increasing order; see
+    /// `missing_ranges`.
+    #[must_use]
+    pub fn missing_packets(&self) -> Vec<u16> {
+        self.missing_ranges().into_iter().flatten().collect()
     }
 
-    fn process_data_packet(&mut self, data: Data) {
-        self.packets.insert(data.packet_number, data.data);
-        if data.is_last_packet {
-            self.expected_number_of_packets = Some((data.packet_number as usize) + 1);
+    #[must_use]
+    pub fn gap_report(&self) -> GapReport {
+        GapReport {
+            header_missing: self.header_missing(),
+            last_packet_number: self.last_packet_number(),
+            missing: self.missing_ranges(),
         }
     }
 
-    /// # Panics
+    #[must_use]
+    pub fn progress(&self) -> FileProgress {
+        FileProgress {
+            file_name: self.file_name.clone(),
+            packets_received: self.packets_received(),
+            expected_packets: self.expected_number_of_packets,
+            bytes_received: self.bytes_received,
+        }
+    }
+
+    /// Add `packet` to this group, reporting whether it was new or a
+    /// repeat of a packet we'd already seen.
     ///
-    /// Will panic if any of the following is true:
-    ///   * The file name hasn't been set
-    ///   * The expected number of packets hasn't set
-    ///   * A packet number was too big to fit in a u16, i.e., the expected
-    ///     number of packets was too large
-    ///   * There's a missing packet in the `packets` map
+    /// # Errors
+    ///
+    /// Will return `Err` if
+    ///   * the conflict policy is `Error` and a data packet with the
+    ///     same number but different contents has already arrived (the
+    ///     earlier packet is kept)
+    ///   * the packet's number comes after the last packet's number
+    ///   * the packet claims to be the last packet but a different
+    ///     packet already did
+    ///   * the packet is a header with a different file name than the
+    ///     one we already have and the header change policy is `Reject`
+    ///   * the packet store failed (see `PacketStore`)
+    ///
+    /// Packets rejected for the last three reasons aren't stored, so
+    /// they can't keep `received_all_packets` from ever becoming true.
+    pub fn process_packet(&mut self, packet: Packet) -> Result<PacketOutcome, ProcessError> {
+        let outcome = match packet {
+            Packet::Header(header) => self.process_header_packet(Cow::Owned(header.file_name)),
+            Packet::Data(data) => {
+                self.process_data_packet(data.packet_number, data.is_last_packet, &data.data)
+            }
+        };
+        self.record_outcome(outcome)
+    }
+
+    /// Like `process_packet`, but for a packet that's still in the
+    /// receive buffer. Only the file name or data we keep is copied.
     ///
     /// # Errors
     ///
-    /// Will return an error if either:
-    ///   * We couldn't open the file
-    ///   * There was an error writing to the file
-    pub fn write_file(&self) -> io::Result<()> {
-        let mut file = File::create(self.file_name.as_ref().unwrap())?;
-        for packet_number in 0..self.expected_number_of_packets.unwrap() {
-            let packet_number: u16 =
-                u16::try_from(packet_number).expect("The packet number should fit in a u16");
-            let packet = self
+    /// Will return the same errors as `process_packet`.
+    pub fn process_packet_ref(
+        &mut self,
+        packet: PacketRef<'_>,
+    ) -> Result<PacketOutcome, ProcessError> {
+        let outcome = match packet {
+            PacketRef::Header(header) => self.process_header_packet(header.file_name()),
+            PacketRef::Data(data) => {
+                self.process_data_packet(data.packet_number(), data.is_last_packet(), data.data())
+            }
+        };
+        self.record_outcome(outcome)
+    }
+
+    const fn record_outcome(
+        &mut self,
+        outcome: Result<PacketOutcome, ProcessError>,
+    ) -> Result<PacketOutcome, ProcessError> {
+        match &outcome {
+            Ok(outcome) => self.stats.record(outcome),
+            Err(ProcessError::ConflictingPacket { .. }) => {
+                self.stats.record(&PacketOutcome::Conflict);
+            }
+            Err(
+                ProcessError::BeyondLastPacket { .. }
+                | ProcessError::LastPacketBeforeWritten { .. }
+                | ProcessError::ConflictingLastPacket { .. }
+                | ProcessError::FileNameChanged { .. }
+                | ProcessError::StoreFailed { .. },
+            ) => self.stats.rejected += 1,
+        }
+        outcome
+    }
+
+    fn process_header_packet(
+        &mut self,
+        file_name: Cow<'_, OsStr>,
+    ) -> Result<PacketOutcome, ProcessError> {
+        let Some(old_name) = &self.file_name else {
+            self.file_name = Some(file_name.into_owned());
+            return Ok(PacketOutcome::New);
+        };
+        if *old_name == *file_name {
+            return Ok(PacketOutcome::Duplicate);
+        }
+        match self.header_change_policy {
+            HeaderChangePolicy::Reject => Err(ProcessError::FileNameChanged {
+                old_name: old_name.clone(),
+                new_name: file_name.into_owned(),
+            }),
+            HeaderChangePolicy::Restart => {
+                // Any data packets that arrived before this header may
+                // belong to the new transfer, but we can't tell them
+                // apart from the old one's, so they go too. So does
+                // whatever we've streamed to disk for the old transfer
+                // (dropping the stream removes its temporary file).
+                let mut packets = mem::take(&mut self.packets);
+                packets.clear();
+                *self = Self {
+                    file_name: Some(file_name.into_owned()),
+                    packets,
+                    conflict_policy: self.conflict_policy,
+                    header_change_policy: self.header_change_policy,
+                    stats: self.stats,
+                    ..Self::default()
+                };
+                Ok(PacketOutcome::Restarted)
+            }
+        }
+    }
+
+    fn check_against_last_packet(
+        &self,
+        packet_number: u16,
+        is_last_packet: bool,
+    ) -> Result<(), ProcessError> {
+        match self.last_packet_number() {
+            Some(last_packet_number) if is_last_packet && packet_number != last_packet_number => {
+                Err(ProcessError::ConflictingLastPacket {
+                    last_packet_number,
+                    packet_number,
+                })
+            }
+            Some(last_packet_number) if packet_number > last_packet_number => {
+                Err(ProcessError::BeyondLastPacket {
+                    packet_number,
+                    last_packet_number,
+                })
+            }
+            None if is_last_packet => {
+                // Packets we're still holding that turn out to be after
+                // this one were strays, and `process_data_packet` drops
+                // them. We can't take back ones we've written to disk,
+                // though.
+                if self.packets_written > usize::from(packet_number) + 1 {
+                    Err(ProcessError::LastPacketBeforeWritten {
+                        last_packet_number: packet_number,
+                        packets_written: self.packets_written,
+                    })
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The last packet has just arrived, so any packets we stored with
+    // higher numbers were strays. Returns the ones we dropped.
+    fn drop_packets_after(&mut self, last_packet_number: u16) -> io::Result<Vec<u16>> {
+        let mut dropped = Vec::new();
+        for packet_number in self.packets.packet_numbers() {
+            if packet_number <= last_packet_number {
+                continue;
+            }
+            let len = self
                 .packets
-                .get(&packet_number)
-                .expect("Didn't find an expected packet");
-            file.write_all(packet)?;
+                .get(packet_number)?
+                .map_or(0, |data| data.len());
+            self.packets.remove(packet_number);
+            self.bytes_received -= len;
+            self.stats.rejected += 1;
+            dropped.push(packet_number);
+        }
+        Ok(dropped)
+    }
+
+    // The store copies `data` if we keep it.
+    fn process_data_packet(
+        &mut self,
+        packet_number: u16,
+        is_last_packet: bool,
+        data: &[u8],
+    ) -> Result<PacketOutcome, ProcessError> {
+        self.check_against_last_packet(packet_number, is_last_packet)?;
+        let dropped = if is_last_packet && self.expected_number_of_packets.is_none() {
+            self.expected_number_of_packets = Some((packet_number as usize) + 1);
+            self.drop_packets_after(packet_number)
+                .map_err(|e| ProcessError::store_failed(packet_number, &e))?
+        } else {
+            Vec::new()
+        };
+        if (packet_number as usize) < self.packets_written {
+            // We've already written this packet and thrown it away, so
+            // we can't tell whether it's a conflict.
+            return Ok(PacketOutcome::Duplicate);
+        }
+        let store_failed = |e| ProcessError::store_failed(packet_number, &e);
+        let Some(old_data) = self.packets.get(packet_number).map_err(store_failed)? else {
+            let len = data.len();
+            self.packets
+                .insert(packet_number, data)
+                .map_err(store_failed)?;
+            self.bytes_received += len;
+            if !dropped.is_empty() {
+                return Ok(PacketOutcome::DroppedLaterPackets(dropped));
+            }
+            return Ok(PacketOutcome::New);
+        };
+        if *old_data == *data {
+            return Ok(PacketOutcome::Duplicate);
         }
+        let old_len = old_data.len();
+        match self.conflict_policy {
+            ConflictPolicy::KeepFirst => {}
+            ConflictPolicy::KeepLast => {
+                let len = data.len();
+                self.packets
+                    .insert(packet_number, data)
+                    .map_err(store_failed)?;
+                self.bytes_received = self.bytes_received - old_len + len;
+            }
+            ConflictPolicy::Error => return Err(ProcessError::ConflictingPacket { packet_number }),
+        }
+        Ok(PacketOutcome::Conflict)
+    }
+
+    /// Stream the file to disk: write every packet at the start of the
+    /// file that has arrived and stop buffering it, so that we only
+    /// hold on to packets that arrived out of order. The file is
+    /// created (as described by `config`) the first time there's
+    /// something to write, which can't happen before the header has
+    /// arrived. Call this after each packet and the memory used is
+    /// bounded by how far out of order the packets arrive.
+    ///
+    /// Returns the number of packets we wrote. Once a group has started
+    /// streaming, `write_file` just finishes the file it started.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if we couldn't create the file (see
+    /// `write_file`) or couldn't write to it. Once a write has failed
+    /// the file is abandoned (we might have written part of a packet),
+    /// and this and `write_file` return `WriteError::StreamFailed`.
+    pub fn write_prefix(
+        &mut self,
+        file_id: u8,
+        config: &OutputConfig,
+    ) -> Result<usize, WriteError> {
+        if self.stream == Some(Stream::Failed) {
+            return Err(WriteError::StreamFailed);
+        }
+        let next_packet_number = |packets_written| u16::try_from(packets_written).ok();
+        if self.file_name.is_none()
+            || !next_packet_number(self.packets_written)
+                .is_some_and(|packet_number| self.packets.contains(packet_number))
+        {
+            return Ok(0);
+        }
+        if matches!(self.stream, None | Some(Stream::Restored(_))) {
+            self.open_stream(file_id, config)?;
+        }
+
+        let mut written = 0;
+        while let Some(packet_number) = next_packet_number(self.packets_written)
+            .filter(|&packet_number| self.packets.contains(packet_number))
+        {
+            if let Some(data) = self.packets.get(packet_number)? {
+                if let Some(Stream::Writing(file)) = &mut self.stream {
+                    if let Err(e) = file.write_all(&data) {
+                        // Dropping the file removes it.
+                        self.stream = Some(Stream::Failed);
+                        return Err(e.into());
+                    }
+                }
+                self.prefix_digest.update(&data);
+            }
+            self.packets.remove(packet_number);
+            self.packets_written += 1;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // Create the file we stream to, starting with whatever we'd written
+    // before a checkpoint was restored. If writing that fails we still
+    // have it, so we can try again.
+    fn open_stream(&mut self, file_id: u8, config: &OutputConfig) -> Result<(), WriteError> {
+        let file_name = self.file_name.as_ref().ok_or(WriteError::MissingFileName)?;
+        let Some(mut file) = config.create_file(file_id, file_name)? else {
+            self.stream = Some(Stream::Skipped);
+            return Ok(());
+        };
+        if let Some(Stream::Restored(bytes)) = &self.stream {
+            file.write_all(bytes)?;
+        }
+        self.stream = Some(Stream::Writing(file));
         Ok(())
     }
+
+    // `expected_number_of_packets` is at most `MAX_PACKETS`, so there's
+    // a packet number for each of them.
+    fn unwritten_packet_numbers(
+        &self,
+        expected_number_of_packets: usize,
+    ) -> impl Iterator<Item = u16> {
+        (0..=u16::MAX)
+            .take(expected_number_of_packets)
+            .skip(self.packets_written)
+    }
+
+    // The number of packets in the file, if we're still holding every
+    // one of them.
+    fn readable_packets(&self) -> Result<usize, WriteError> {
+        let expected_number_of_packets = self
+            .expected_number_of_packets
+            .ok_or(WriteError::UnknownLength)?;
+        let missing_packets = self.missing_packets();
+        if !missing_packets.is_empty() {
+            return Err(WriteError::MissingPackets(missing_packets));
+        }
+        if self.packets_written > 0 {
+            return Err(WriteError::AlreadyWritten);
+        }
+        Ok(expected_number_of_packets)
+    }
+
+    /// Write the reassembled file to `writer` instead of to disk. This
+    /// doesn't need the header, and can be called any number of times
+    /// until the file is written with `write_prefix` or `finish`.
+    ///
+    /// Returns the size and SHA-256 hash of what we wrote.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we don't have every packet (see
+    /// `write_file`), have already written some of them to disk, or
+    /// couldn't read a packet back from the packet store or write it
+    /// to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<FileDigest, WriteError> {
+        let expected_number_of_packets = self.readable_packets()?;
+        let mut digest = DigestBuilder::default();
+        for packet_number in self.unwritten_packet_numbers(expected_number_of_packets) {
+            if let Some(data) = self.packets.get(packet_number)? {
+                writer.write_all(&data)?;
+                digest.update(&data);
+            }
+        }
+        writer.flush()?;
+        Ok(digest.finish())
+    }
+
+    /// A reader for the reassembled file, which reads it a packet at a
+    /// time instead of copying it all at once.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as `write_to`,
+    /// except that errors reading from the packet store come from the
+    /// reader.
+    pub fn reader(&self) -> Result<FileReader<'_>, WriteError> {
+        let expected_number_of_packets = self.readable_packets()?;
+        Ok(FileReader {
+            packets: &*self.packets,
+            packet_numbers: (0..=u16::MAX).take(expected_number_of_packets),
+            current: Cow::Borrowed(&[]),
+            position: 0,
+        })
+    }
+
+    /// The size and SHA-256 hash of the reassembled file. This is
+    /// computed while the file is written, and still available after
+    /// `finish` has thrown away its packets.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if we don't have every packet yet (see
+    /// `write_file`) or couldn't read one back from the packet store.
+    pub fn digest(&self) -> Result<FileDigest, WriteError> {
+        if let Some(digest) = self.digest {
+            return Ok(digest);
+        }
+        let expected_number_of_packets = self
+            .expected_number_of_packets
+            .ok_or(WriteError::UnknownLength)?;
+        let missing_packets = self.missing_packets();
+        if !missing_packets.is_empty() {
+            return Err(WriteError::MissingPackets(missing_packets));
+        }
+        let mut digest = self.prefix_digest.clone();
+        for packet_number in self.unwritten_packet_numbers(expected_number_of_packets) {
+            if let Some(data) = self.packets.get(packet_number)? {
+                digest.update(&data);
+            }
+        }
+        Ok(digest.finish())
+    }
+
+    /// Write the reassembled file for `file_id` into the directory
+    /// described by `config`, naming it with the configured template.
+    /// The name is sanitized first so that a hostile header can't
+    /// write outside of that directory.
+    ///
+    /// The file is written under a temporary name and renamed into
+    /// place once it's complete (see `OutputConfig::with_sync`), so
+    /// nothing ever sees a partially written file under its real name.
+    ///
+    /// Returns the path we wrote to, or `None` if the file already
+    /// existed and the collision policy said to skip it.
+    ///
+    /// # Errors
+    ///
+    /// Will return an error if any of the following is true:
+    ///   * The file name hasn't been set
+    ///   * The expected number of packets hasn't been set
+    ///   * There are missing packets in the `packets` map
+    ///   * The file name isn't safe to write inside the output directory
+    ///     (see `sanitize_file_name`)
+    ///   * The file already exists and the collision policy is `Error`
+    ///   * We couldn't open the file
+    ///   * There was an error writing to the file or moving it into
+    ///     place
+    ///
+    /// We check for missing data before creating the file, so none of
+    /// the first three create a temporary file. If we'd started
+    /// streaming the file with `write_prefix` and writing it fails, the
+    /// packets we'd already streamed are lost, and later calls return
+    /// `WriteError::StreamFailed`. If we'd streamed all of it and only
+    /// moving it into place fails (because the file appeared meanwhile,
+    /// say), we keep the temporary file and later calls try again.
+    pub fn write_file(
+        &mut self,
+        file_id: u8,
+        config: &OutputConfig,
+    ) -> Result<Option<PathBuf>, WriteError> {
+        let file_name = self.file_name.clone().ok_or(WriteError::MissingFileName)?;
+        let expected_number_of_packets = self
+            .expected_number_of_packets
+            .ok_or(WriteError::UnknownLength)?;
+        let missing_packets = self.missing_packets();
+        if !missing_packets.is_empty() {
+            return Err(WriteError::MissingPackets(missing_packets));
+        }
+
+        match &self.stream {
+            Some(Stream::Skipped) => return Ok(None),
+            Some(Stream::Finished(path)) => return Ok(path.clone()),
+            Some(Stream::Failed) => return Err(WriteError::StreamFailed),
+            // The packets we've written are only in the stream.
+            None if self.packets_written > 0 => return Err(WriteError::AlreadyWritten),
+            Some(Stream::Writing(_) | Stream::Restored(_)) | None => {}
+        }
+        if self.stream.is_some() {
+            if matches!(self.stream, Some(Stream::Restored(_))) {
+                self.open_stream(file_id, config)?;
+            }
+            // Stream the rest of the file, so that if moving it into
+            // place fails the temporary file has everything and we can
+            // just try again.
+            self.write_prefix(file_id, config)?;
+            let path = match &self.stream {
+                Some(Stream::Writing(file)) => file.commit()?,
+                // The collision policy skipped the file when we
+                // reopened it.
+                _ => None,
+            };
+            self.digest = Some(self.prefix_digest.clone().finish());
+            self.stream = Some(Stream::Finished(path.clone()));
+            return Ok(path);
+        }
+        let Some(file) = config.create_file(file_id, &file_name)? else {
+            return Ok(None);
+        };
+        let (path, digest) = self.commit_file(file, expected_number_of_packets)?;
+        self.digest = Some(digest);
+        Ok(path)
+    }
+
+    // Write every packet to `file` (which we haven't streamed anything
+    // to) and move it into place.
+    fn commit_file(
+        &self,
+        mut file: OutputFile,
+        expected_number_of_packets: usize,
+    ) -> Result<(Option<PathBuf>, FileDigest), WriteError> {
+        let mut digest = self.prefix_digest.clone();
+        for packet_number in self.unwritten_packet_numbers(expected_number_of_packets) {
+            if let Some(data) = self.packets.get(packet_number)? {
+                file.write_all(&data)?;
+                digest.update(&data);
+            }
+        }
+        Ok((file.commit()?, digest.finish()))
+    }
+
+    /// Write the file (see `write_file`) and then throw away its
+    /// packets to free up their memory. Later calls to `write_file`
+    /// won't write it again; they just return the same path.
+    ///
+    /// # Errors
+    ///
+    /// Will return the same errors as `write_file`. If it fails we keep
+    /// the packets so that we can try again.
+    pub fn finish(
+        &mut self,
+        file_id: u8,
+        config: &OutputConfig,
+    ) -> Result<Option<PathBuf>, WriteError> {
+        let path = self.write_file(file_id, config)?;
+        // We can't hash the file once its packets are gone.
+        self.digest = Some(self.digest()?);
+        self.packets_written += self.packets.len();
+        self.packets.clear();
+        self.stream = Some(Stream::Finished(path.clone()));
+        Ok(path)
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod write_file_tests {
+    use std::fs;
+
+    use crate::{
+        file_name::FileNameError,
+        output::{OutputConfig, WriteError},
+        test_helpers::{add, data, header, output_dir},
+    };
+
+    use super::PacketGroup;
+
+    fn packet_group(file_name: &str) -> PacketGroup {
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, file_name));
+        add(&mut group, data(0, 0, true, b"hello"));
+        group
+    }
+
+    #[test]
+    fn writes_inside_output_dir() {
+        let (output_dir, config) = output_dir();
+        let path = packet_group("docs/hello.txt")
+            .write_file(0, &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(output_dir.path().join("docs").join("hello.txt"), path);
+        assert_eq!(b"hello".to_vec(), fs::read(path).unwrap());
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let root = tempfile::tempdir().unwrap();
+        let output_dir = root.path().join("output");
+        fs::create_dir(&output_dir).unwrap();
+        let error = packet_group("../escaped.txt")
+            .write_file(0, &OutputConfig::new(&output_dir))
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            WriteError::InvalidFileName(FileNameError::ParentDirectory)
+        ));
+        assert!(!root.path().join("escaped.txt").exists());
+    }
+
+    #[test]
+    fn rejects_absolute_path() {
+        let (_output_dir, config) = output_dir();
+        let error = packet_group("/tmp/escaped.txt")
+            .write_file(0, &config)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            WriteError::InvalidFileName(FileNameError::AbsolutePath)
+        ));
+    }
+
+    #[test]
+    fn error_on_missing_file_name() {
+        let (_output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add(&mut group, data(0, 0, true, b"hello"));
+        let error = group.write_file(0, &config).unwrap_err();
+        assert!(matches!(error, WriteError::MissingFileName));
+    }
+
+    #[test]
+    fn error_on_unknown_length() {
+        let (output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "hello.txt"));
+        let error = group.write_file(0, &config).unwrap_err();
+        assert!(matches!(error, WriteError::UnknownLength));
+        assert!(!output_dir.path().join("hello.txt").exists());
+    }
+
+    #[test]
+    fn error_on_missing_packets() {
+        let (output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "hello.txt"));
+        add(&mut group, data(0, 0, false, b"hello"));
+        add(&mut group, data(0, 4, true, b"!"));
+        add(&mut group, data(0, 2, false, b"world"));
+        let error = group.write_file(0, &config).unwrap_err();
+        assert!(matches!(error, WriteError::MissingPackets(missing) if missing == vec![1, 3]));
+        assert!(!output_dir.path().join("hello.txt").exists());
+    }
+}
+
+#[cfg(test)]
+mod progress_tests {
+    use crate::test_helpers::{add, data, header};
+
+    use super::PacketGroup;
+
+    #[test]
+    fn empty_group_has_no_progress() {
+        let progress = PacketGroup::default().progress();
+        assert_eq!(progress.file_name, None);
+        assert_eq!(progress.packets_received, 0);
+        assert_eq!(progress.expected_packets, None);
+        assert_eq!(progress.bytes_received, 0);
+        assert_eq!(progress.percentage(), None);
+    }
+
+    #[test]
+    fn counts_packets_and_bytes() {
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "small.txt"));
+        add(&mut group, data(0, 0, false, b"hello"));
+        add(&mut group, data(0, 3, true, b"!"));
+        let progress = group.progress();
+        assert_eq!(progress.file_name, Some("small.txt".into()));
+        assert_eq!(progress.packets_received, 2);
+        assert_eq!(progress.expected_packets, Some(4));
+        assert_eq!(progress.bytes_received, 6);
+        assert_eq!(progress.percentage(), Some(50.0));
+    }
+
+    #[test]
+    fn repeated_packets_are_only_counted_once() {
+        let mut group = PacketGroup::default();
+        add(&mut group, data(0, 0, false, b"hello"));
+        add(&mut group, data(0, 0, false, b"hello"));
+        let progress = group.progress();
+        assert_eq!(progress.packets_received, 1);
+        assert_eq!(progress.bytes_received, 5);
+    }
+}
+
+#[cfg(test)]
+mod gap_report_tests {
+    use std::collections::HashSet;
+
+    use crate::test_helpers::{add, data, header};
+
+    use super::{GapReport, PacketGroup};
+
+    fn add_data(group: &mut PacketGroup, packet_number: u16, is_last_packet: bool) {
+        add(group, data(0, packet_number, is_last_packet, &[1]));
+    }
+
+    #[test]
+    fn empty_group() {
+        let group = PacketGroup::default();
+        assert_eq!(
+            GapReport {
+                header_missing: true,
+                last_packet_number: None,
+                missing: vec![],
+            },
+            group.gap_report()
+        );
+    }
+
+    #[test]
+    fn gaps_below_highest_seen() {
+        let mut group = PacketGroup::default();
+        for packet_number in [1, 2, 5, 9] {
+            add_data(&mut group, packet_number, false);
+        }
+        assert_eq!(vec![0..=0, 3..=4, 6..=8], group.missing_ranges());
+        assert_eq!(vec![0, 3, 4, 6, 7, 8], group.missing_packets());
+    }
+
+    #[test]
+    fn gaps_up_to_last_packet() {
+        let mut group = PacketGroup::default();
+        add_data(&mut group, 0, false);
+        add_data(&mut group, 2, false);
+        add_data(&mut group, 6, true);
+        assert_eq!(vec![1..=1, 3..=5], group.missing_ranges());
+    }
+
+    #[test]
+    fn complete_group() {
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "small.txt"));
+        add_data(&mut group, 1, true);
+        add_data(&mut group, 0, false);
+        let report = group.gap_report();
+        assert!(report.is_complete());
+        assert_eq!(Some(1), report.last_packet_number);
+        assert_eq!("complete", report.to_string());
+    }
+
+    #[test]
+    fn highest_possible_packet_number() {
+        let mut group = PacketGroup::default();
+        add_data(&mut group, u16::MAX - 1, false);
+        add_data(&mut group, u16::MAX, true);
+        let missing = group.missing_ranges();
+        assert_eq!(vec![0..=u16::MAX - 2], missing);
+    }
+
+    #[test]
+    fn display_lists_problems() {
+        let mut group = PacketGroup::default();
+        add_data(&mut group, 1, false);
+        add_data(&mut group, 5, false);
+        assert_eq!(
+            "header missing; last packet missing; missing packets 0, 2-4",
+            group.gap_report().to_string()
+        );
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn missing_and_received_cover_everything(packet_numbers: Vec<u16>, last: u16) -> bool {
+        // Keep files small enough that checking every packet stays fast.
+        let last = last % 1024;
+        let mut group = PacketGroup::default();
+        for packet_number in packet_numbers {
+            add_data(&mut group, packet_number % (last + 1), false);
+        }
+        add_data(&mut group, last, true);
+        let missing: HashSet<u16> = group.missing_packets().into_iter().collect();
+        (0..=last).all(|packet_number| {
+            missing.contains(&packet_number) != group.packets.contains(packet_number)
+        })
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod outcome_tests {
+    use crate::test_helpers::{data, header};
+
+    use super::{ConflictPolicy, PacketGroup, PacketOutcome, PacketStats, ProcessError};
+
+    #[test]
+    fn new_and_duplicate_packets() {
+        let mut group = PacketGroup::default();
+        assert_eq!(
+            group.process_packet(header(0, "small.txt")),
+            Ok(PacketOutcome::New)
+        );
+        assert_eq!(
+            group.process_packet(header(0, "small.txt")),
+            Ok(PacketOutcome::Duplicate)
+        );
+        assert_eq!(
+            group.process_packet(data(0, 0, false, b"hi")),
+            Ok(PacketOutcome::New)
+        );
+        assert_eq!(
+            group.process_packet(data(0, 0, false, b"hi")),
+            Ok(PacketOutcome::Duplicate)
+        );
+        assert_eq!(
+            group.stats(),
+            PacketStats {
+                new: 2,
+                duplicates: 2,
+                conflicts: 0,
+                rejected: 0,
+                restarts: 0
+            }
+        );
+    }
+
+    #[test]
+    fn keep_first_ignores_conflicting_packet() {
+        let mut group = PacketGroup::default().with_conflict_policy(ConflictPolicy::KeepFirst);
+        group.process_packet(data(0, 0, false, b"first")).unwrap();
+        assert_eq!(
+            group.process_packet(data(0, 0, false, b"last")),
+            Ok(PacketOutcome::Conflict)
+        );
+        assert_eq!(&*group.packets.get(0).unwrap().unwrap(), b"first");
+        assert_eq!(group.stats().conflicts, 1);
+    }
+
+    #[test]
+    fn keep_last_replaces_conflicting_packet() {
+        let mut group = PacketGroup::default().with_conflict_policy(ConflictPolicy::KeepLast);
+        group.process_packet(data(0, 0, false, b"first")).unwrap();
+        assert_eq!(
+            group.process_packet(data(0, 0, false, b"last")),
+            Ok(PacketOutcome::Conflict)
+        );
+        assert_eq!(&*group.packets.get(0).unwrap().unwrap(), b"last");
+        assert_eq!(group.progress().bytes_received, 4);
+    }
+
+    #[test]
+    fn error_policy_reports_conflicting_packet() {
+        let mut group = PacketGroup::default().with_conflict_policy(ConflictPolicy::Error);
+        group.process_packet(data(0, 7, false, b"first")).unwrap();
+        assert_eq!(
+            group.process_packet(data(0, 7, false, b"last")),
+            Err(ProcessError::ConflictingPacket { packet_number: 7 })
+        );
+        assert_eq!(&*group.packets.get(7).unwrap().unwrap(), b"first");
+        assert_eq!(group.stats().conflicts, 1);
+    }
+
+    #[test]
+    fn parses_conflict_policies() {
+        assert_eq!("keep-first".parse(), Ok(ConflictPolicy::KeepFirst));
+        assert_eq!("keep-last".parse(), Ok(ConflictPolicy::KeepLast));
+        assert_eq!("error".parse(), Ok(ConflictPolicy::Error));
+        assert!("newest".parse::<ConflictPolicy>().is_err());
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod last_packet_tests {
+    use crate::test_helpers::{add, data, header, output_dir};
+
+    use super::{PacketGroup, PacketOutcome, ProcessError};
+
+    fn add_data(
+        group: &mut PacketGroup,
+        packet_number: u16,
+        is_last_packet: bool,
+    ) -> Result<PacketOutcome, ProcessError> {
+        group.process_packet(data(0, packet_number, is_last_packet, &[0]))
+    }
+
+    #[test]
+    fn packet_after_last_packet_is_rejected() {
+        let mut group = PacketGroup::default();
+        assert_eq!(add_data(&mut group, 2, true), Ok(PacketOutcome::New));
+        assert_eq!(
+            add_data(&mut group, 3, false),
+            Err(ProcessError::BeyondLastPacket {
+                packet_number: 3,
+                last_packet_number: 2
+            })
+        );
+        assert!(!group.packets.contains(3));
+        assert_eq!(group.stats().rejected, 1);
+
+        assert_eq!(add_data(&mut group, 0, false), Ok(PacketOutcome::New));
+        assert_eq!(add_data(&mut group, 1, false), Ok(PacketOutcome::New));
+        assert!(group.received_all_packets());
+    }
+
+    #[test]
+    fn stray_packets_before_the_last_packet_are_dropped() {
+        let mut group = PacketGroup::default();
+        assert_eq!(add_data(&mut group, 5, false), Ok(PacketOutcome::New));
+        assert_eq!(add_data(&mut group, 0, false), Ok(PacketOutcome::New));
+        assert_eq!(
+            add_data(&mut group, 2, true),
+            Ok(PacketOutcome::DroppedLaterPackets(vec![5]))
+        );
+        assert_eq!(group.expected_number_of_packets, Some(3));
+        assert!(!group.packets.contains(5));
+        assert_eq!(group.bytes_received, 2);
+        assert_eq!(group.stats().new, 3);
+        assert_eq!(group.stats().rejected, 1);
+        assert_eq!(group.missing_packets(), vec![1]);
+
+        assert_eq!(add_data(&mut group, 1, false), Ok(PacketOutcome::New));
+        assert!(group.received_all_packets());
+    }
+
+    #[test]
+    fn last_packet_before_written_packets_is_rejected() {
+        let (_output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "file.txt"));
+        for packet_number in 0..3 {
+            add_data(&mut group, packet_number, false).unwrap();
+        }
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 3);
+        assert_eq!(
+            add_data(&mut group, 1, true),
+            Err(ProcessError::LastPacketBeforeWritten {
+                last_packet_number: 1,
+                packets_written: 3
+            })
+        );
+        assert_eq!(group.expected_number_of_packets, None);
+        assert_eq!(group.stats().rejected, 1);
+    }
+
+    #[test]
+    fn second_last_packet_is_rejected() {
+        let mut group = PacketGroup::default();
+        assert_eq!(add_data(&mut group, 1, true), Ok(PacketOutcome::New));
+        assert_eq!(
+            add_data(&mut group, 0, true),
+            Err(ProcessError::ConflictingLastPacket {
+                last_packet_number: 1,
+                packet_number: 0
+            })
+        );
+        assert_eq!(group.expected_number_of_packets, Some(2));
+        assert!(!group.packets.contains(0));
+    }
+
+    #[test]
+    fn repeated_last_packet_is_a_duplicate() {
+        let mut group = PacketGroup::default();
+        assert_eq!(add_data(&mut group, 1, true), Ok(PacketOutcome::New));
+        assert_eq!(add_data(&mut group, 1, true), Ok(PacketOutcome::Duplicate));
+        assert_eq!(add_data(&mut group, 1, false), Ok(PacketOutcome::Duplicate));
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod header_change_tests {
+    use crate::{packets::Packet, test_helpers::data};
+
+    use super::{HeaderChangePolicy, PacketGroup, PacketOutcome, ProcessError};
+
+    fn header(file_name: &str) -> Packet {
+        crate::test_helpers::header(3, file_name)
+    }
+
+    fn last_data() -> Packet {
+        data(3, 0, true, b"old")
+    }
+
+    #[test]
+    fn reject_keeps_the_original_name() {
+        let mut group =
+            PacketGroup::default().with_header_change_policy(HeaderChangePolicy::Reject);
+        group.process_packet(header("old.txt")).unwrap();
+        group.process_packet(last_data()).unwrap();
+        assert_eq!(
+            group.process_packet(header("new.txt")),
+            Err(ProcessError::FileNameChanged {
+                old_name: "old.txt".into(),
+                new_name: "new.txt".into()
+            })
+        );
+        assert_eq!(group.file_name, Some("old.txt".into()));
+        assert!(group.received_all_packets());
+        assert_eq!(group.stats().rejected, 1);
+    }
+
+    #[test]
+    fn restart_starts_a_fresh_transfer() {
+        let mut group =
+            PacketGroup::default().with_header_change_policy(HeaderChangePolicy::Restart);
+        group.process_packet(header("old.txt")).unwrap();
+        group.process_packet(last_data()).unwrap();
+        assert_eq!(
+            group.process_packet(header("new.txt")),
+            Ok(PacketOutcome::Restarted)
+        );
+        assert_eq!(group.file_name, Some("new.txt".into()));
+        assert!(group.packets.is_empty());
+        assert_eq!(group.expected_number_of_packets, None);
+        assert_eq!(group.progress().bytes_received, 0);
+        assert!(!group.received_all_packets());
+        assert_eq!(group.stats().restarts, 1);
+        assert_eq!(group.header_change_policy, HeaderChangePolicy::Restart);
+    }
+
+    #[test]
+    fn same_name_is_a_duplicate_under_either_policy() {
+        for policy in [HeaderChangePolicy::Reject, HeaderChangePolicy::Restart] {
+            let mut group = PacketGroup::default().with_header_change_policy(policy);
+            group.process_packet(header("same.txt")).unwrap();
+            group.process_packet(last_data()).unwrap();
+            assert_eq!(
+                group.process_packet(header("same.txt")),
+                Ok(PacketOutcome::Duplicate)
+            );
+            assert!(group.received_all_packets());
+        }
+    }
+
+    #[test]
+    fn parses_header_change_policies() {
+        assert_eq!("reject".parse(), Ok(HeaderChangePolicy::Reject));
+        assert_eq!("restart".parse(), Ok(HeaderChangePolicy::Restart));
+        assert!("rename".parse::<HeaderChangePolicy>().is_err());
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod write_prefix_tests {
+    use std::{fs, path::Path};
+
+    use crate::{
+        output::{CollisionPolicy, WriteError, TEMP_FILE_SUFFIX},
+        test_helpers::{add, data, header, output_dir},
+    };
+
+    use super::{PacketGroup, PacketOutcome, Stream};
+
+    // What we've streamed so far, which is still under a temporary name.
+    fn temp_file_contents(directory: &Path) -> Vec<u8> {
+        let entry = fs::read_dir(directory)
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(TEMP_FILE_SUFFIX)
+            })
+            .unwrap();
+        fs::read(entry.path()).unwrap()
+    }
+
+    fn add_header(group: &mut PacketGroup) {
+        add(group, header(0, "streamed.txt"));
+    }
+
+    // Packet 0 is "a", packet 1 is "b", and so on.
+    fn add_data(
+        group: &mut PacketGroup,
+        packet_number: u16,
+        is_last_packet: bool,
+    ) -> PacketOutcome {
+        let contents = [b'a' + u8::try_from(packet_number).unwrap()];
+        add(group, data(0, packet_number, is_last_packet, &contents))
+    }
+
+    #[test]
+    fn writes_contiguous_prefix_as_it_arrives() {
+        let (output_dir, config) = output_dir();
+        let path = output_dir.path().join("streamed.txt");
+        let mut group = PacketGroup::default();
+
+        add_data(&mut group, 1, false);
+        add_data(&mut group, 0, false);
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 0);
+        assert!(!path.exists());
+
+        add_header(&mut group);
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 2);
+        assert_eq!(temp_file_contents(output_dir.path()), b"ab");
+        assert!(!path.exists());
+        assert!(group.packets.is_empty());
+
+        add_data(&mut group, 3, true);
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 0);
+        assert_eq!(group.packets.len(), 1);
+        assert_eq!(group.missing_packets(), vec![2]);
+        assert!(!group.received_all_packets());
+
+        add_data(&mut group, 2, false);
+        assert!(group.received_all_packets());
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 2);
+        assert!(group.packets.is_empty());
+        assert_eq!(temp_file_contents(output_dir.path()), b"abcd");
+        assert_eq!(group.progress().packets_received, 4);
+        assert_eq!(group.progress().bytes_received, 4);
+
+        assert_eq!(group.write_file(0, &config).unwrap(), Some(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), b"abcd");
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_file_finishes_a_streamed_file() {
+        let (output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add_header(&mut group);
+        add_data(&mut group, 0, false);
+        group.write_prefix(0, &config).unwrap();
+        add_data(&mut group, 2, true);
+        add_data(&mut group, 1, false);
+
+        let path = group.write_file(0, &config).unwrap().unwrap();
+        assert_eq!(path, output_dir.path().join("streamed.txt"));
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn written_packets_are_duplicates() {
+        let (_output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add_header(&mut group);
+        add_data(&mut group, 0, false);
+        group.write_prefix(0, &config).unwrap();
+        assert_eq!(add_data(&mut group, 0, false), PacketOutcome::Duplicate);
+        assert!(group.packets.is_empty());
+    }
+
+    #[test]
+    fn skipped_file_is_left_alone() {
+        let (output_dir, config) = output_dir();
+        let path = output_dir.path().join("streamed.txt");
+        fs::write(&path, "original").unwrap();
+        let config = config.with_collision_policy(CollisionPolicy::Skip);
+        let mut group = PacketGroup::default();
+        add_header(&mut group);
+        add_data(&mut group, 0, true);
+
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 1);
+        assert!(group.packets.is_empty());
+        assert_eq!(group.write_file(0, &config).unwrap(), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+    }
+
+    #[test]
+    fn failed_writes_abandon_the_file() {
+        let (output_dir, config) = output_dir();
+        let mut group = PacketGroup::default();
+        add_header(&mut group);
+        add_data(&mut group, 0, false);
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 1);
+
+        let Some(Stream::Writing(file)) = &mut group.stream else {
+            panic!("the file should be streaming");
+        };
+        file.fail_writes().unwrap();
+        add_data(&mut group, 1, false);
+        assert!(matches!(
+            group.write_prefix(0, &config),
+            Err(WriteError::Io(_))
+        ));
+        assert_eq!(group.stream, Some(Stream::Failed));
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 0);
+
+        // Retrying can't duplicate what was written before the failure.
+        add_data(&mut group, 2, true);
+        assert!(matches!(
+            group.write_prefix(0, &config),
+            Err(WriteError::StreamFailed)
+        ));
+        assert!(matches!(
+            group.write_file(0, &config),
+            Err(WriteError::StreamFailed)
+        ));
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_commit_can_be_retried() {
+        let (output_dir, config) = output_dir();
+        let path = output_dir.path().join("streamed.txt");
+        let config = config.with_collision_policy(CollisionPolicy::Error);
+        let mut group = PacketGroup::default();
+        add_header(&mut group);
+        add_data(&mut group, 0, false);
+        add_data(&mut group, 1, false);
+        assert_eq!(group.write_prefix(0, &config).unwrap(), 2);
+
+        add_data(&mut group, 2, true);
+        fs::write(&path, "in the way").unwrap();
+        assert!(matches!(
+            group.finish(0, &config),
+            Err(WriteError::AlreadyExists(_))
+        ));
+        assert!(matches!(group.stream, Some(Stream::Writing(_))));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(group.finish(0, &config).unwrap(), Some(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+        assert_eq!(group.digest().unwrap().size, 3);
+        assert_eq!(fs::read_dir(output_dir.path()).unwrap().count(), 1);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod finish_tests {
+    use std::fs;
+
+    use crate::{
+        output::WriteError,
+        test_helpers::{add, data, header, output_dir},
+    };
+
+    use super::{PacketGroup, PacketOutcome};
+
+    fn complete_group() -> PacketGroup {
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "finished.txt"));
+        for packet_number in 0..3 {
+            add(
+                &mut group,
+                data(0, packet_number, packet_number == 2, b"abc"),
+            );
+        }
+        group
+    }
+
+    #[test]
+    fn finish_frees_the_packets() {
+        let (_output_dir, config) = output_dir();
+        let mut group = complete_group();
+        assert!(group.is_complete());
+
+        let path = group.finish(0, &config).unwrap().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcabcabc");
+        assert!(group.packets.is_empty());
+        assert!(group.is_complete());
+        assert_eq!(group.progress().packets_received, 3);
+
+        // A late duplicate doesn't bring anything back, and writing
+        // again doesn't clobber the file.
+        let outcome = add(&mut group, data(0, 1, false, b"abc"));
+        assert_eq!(outcome, PacketOutcome::Duplicate);
+        assert!(group.packets.is_empty());
+        assert_eq!(group.write_file(0, &config).unwrap(), Some(path.clone()));
+        assert_eq!(fs::read(&path).unwrap(), b"abcabcabc");
+    }
+
+    #[test]
+    fn failed_finish_keeps_the_packets() {
+        let (_output_dir, config) = output_dir();
+        let mut group = complete_group();
+        group.packets.remove(1);
+        let error = group.finish(0, &config).unwrap_err();
+        assert!(matches!(error, WriteError::MissingPackets(_)));
+        assert_eq!(group.packets.len(), 2);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod digest_tests {
+    use std::fs;
+
+    use crate::{
+        output::{CollisionPolicy, WriteError},
+        test_helpers::{add, data, header, output_dir},
+    };
+
+    use super::PacketGroup;
+
+    // The SHA-256 hash of "abc".
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn incomplete_files_have_no_digest() {
+        let mut group = PacketGroup::default();
+        add(&mut group, data(0, 1, false, b"b"));
+        assert!(matches!(group.digest(), Err(WriteError::UnknownLength)));
+        add(&mut group, data(0, 2, true, b"c"));
+        assert!(matches!(
+            group.digest(),
+            Err(WriteError::MissingPackets(missing)) if missing == vec![0]
+        ));
+        add(&mut group, data(0, 0, false, b"a"));
+        let digest = group.digest().unwrap();
+        assert_eq!(digest.size, 3);
+        assert_eq!(digest.sha256_hex(), ABC_SHA256);
+        assert_eq!(digest.to_string(), format!("3 bytes, sha256 {ABC_SHA256}"));
+    }
+
+    #[test]
+    fn skipped_files_keep_their_digest() {
+        let (output_dir, config) = output_dir();
+        fs::write(output_dir.path().join("abc.txt"), "old").unwrap();
+        let config = config.with_collision_policy(CollisionPolicy::Skip);
+
+        let mut group = PacketGroup::default();
+        add(&mut group, header(0, "abc.txt"));
+        add(&mut group, data(0, 0, false, b"ab"));
+        add(&mut group, data(0, 1, true, b"c"));
+        assert_eq!(group.finish(0, &config).unwrap(), None);
+        assert!(group.packets.is_empty());
+        assert_eq!(group.digest().unwrap().sha256_hex(), ABC_SHA256);
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod write_to_tests {
+    use std::io::Read;
+
+    use crate::{
+        output::WriteError,
+        packet_store::StorageBackend,
+        test_helpers::{add, data, header, output_dir},
+    };
+
+    use super::PacketGroup;
+
+    // The SHA-256 hash of "abcdef".
+    const ABCDEF_SHA256: &str = "bef57ec7f53a6d40beb640a780a639c83bc29ac8a9816f1fc6c5c6dcd93c4721";
+
+    // "abcdef" in three packets that arrive out of order, without a
+    // header.
+    fn abcdef_group(mut group: PacketGroup) -> PacketGroup {
+        add(&mut group, data(0, 2, true, b"ef"));
+        add(&mut group, data(0, 0, false, b"abc"));
+        add(&mut group, data(0, 1, false, b"d"));
+        group
+    }
+
+    #[test]
+    fn writes_packets_in_order() {
+        let group = abcdef_group(PacketGroup::default());
+        let mut contents = Vec::new();
+        let digest = group.write_to(&mut contents).unwrap();
+        assert_eq!(contents, b"abcdef");
+        assert_eq!(digest.size, 6);
+        assert_eq!(digest.sha256_hex(), ABCDEF_SHA256);
+        // Nothing was used up.
+        assert_eq!(group.packets.len(), 3);
+    }
+
+    #[test]
+    fn incomplete_files_are_rejected() {
+        let mut group = PacketGroup::default();
+        add(&mut group, data(0, 1, true, b"b"));
+        assert!(matches!(
+            group.write_to(Vec::new()),
+            Err(WriteError::MissingPackets(missing)) if missing == vec![0]
+        ));
+        assert!(matches!(
+            group.reader(),
+            Err(WriteError::MissingPackets(missing)) if missing == vec![0]
+        ));
+    }
+
+    #[test]
+    fn reads_across_packets() {
+        let group = abcdef_group(PacketGroup::default());
+        let mut reader = group.reader().unwrap();
+        let mut buf = [0; 2];
+        let mut chunks = Vec::new();
+        loop {
+            let read = reader.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            chunks.push(buf[..read].to_vec());
+        }
+        assert_eq!(chunks, [&b"ab"[..], b"c", b"d", b"ef"]);
+    }
+
+    #[test]
+    fn reads_from_a_spill_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StorageBackend::SpillFile(dir.path().to_path_buf()).create_store();
+        let group = abcdef_group(PacketGroup::default().with_store(store));
+        let mut contents = String::new();
+        group
+            .reader()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "abcdef");
+    }
+
+    #[test]
+    fn written_files_cant_be_read() {
+        let (_output_dir, config) = output_dir();
+        let mut group = abcdef_group(PacketGroup::default());
+        add(&mut group, header(0, "abcdef.txt"));
+        group.finish(0, &config).unwrap();
+        assert!(matches!(
+            group.write_to(Vec::new()),
+            Err(WriteError::AlreadyWritten)
+        ));
+        assert!(matches!(group.reader(), Err(WriteError::AlreadyWritten)));
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod accessor_tests {
+    use std::ffi::OsStr;
+
+    use crate::{
+        output::OutputConfig,
+        packet_store::StorageBackend,
+        packets::{Data, Header},
+    };
+
+    use super::{ConflictPolicy, HeaderChangePolicy, PacketGroup};
+
+    #[test]
+    fn reports_state_through_the_public_api() {
+        let mut group = PacketGroup::default().with_conflict_policy(ConflictPolicy::KeepLast);
+        assert_eq!(group.file_name(), None);
+        assert_eq!(group.expected_number_of_packets(), None);
+        assert_eq!(group.conflict_policy(), ConflictPolicy::KeepLast);
+        assert_eq!(group.header_change_policy(), HeaderChangePolicy::default());
+
+        for packet in [
+            Data::new(0, 1, *b"cd")
+                .unwrap()
+                .with_last_packet(true)
+                .into(),
+            Header::new(0, "abcd.txt").unwrap().into(),
+            Data::new(0, 0, *b"ab").unwrap().into(),
+        ] {
+            group.process_packet(packet).unwrap();
+        }
+        assert_eq!(group.file_name(), Some(OsStr::new("abcd.txt")));
+        assert_eq!(group.expected_number_of_packets(), Some(2));
+        assert_eq!(group.packets_received(), 2);
+        assert_eq!(group.bytes_received(), 4);
+        assert_eq!(group.packets_written(), 0);
+
+        let output_dir = tempfile::tempdir().unwrap();
+        group
+            .finish(0, &OutputConfig::new(output_dir.path()))
+            .unwrap();
+        assert_eq!(group.packets_received(), 2);
+        assert_eq!(group.packets_written(), 2);
+    }
+
+    #[test]
+    fn builders_combine() {
+        let dir = tempfile::tempdir().unwrap();
+        let group = PacketGroup::default()
+            .with_store(StorageBackend::SpillFile(dir.path().to_path_buf()).create_store())
+            .with_conflict_policy(ConflictPolicy::Error)
+            .with_header_change_policy(HeaderChangePolicy::Restart);
+        assert_eq!(group.conflict_policy(), ConflictPolicy::Error);
+        assert_eq!(group.header_change_policy(), HeaderChangePolicy::Restart);
+        assert!(format!("{:?}", group.packets).starts_with("SpillFileStore"));
+    }
 }
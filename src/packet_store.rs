@@ -0,0 +1,788 @@
+//! Where a `PacketGroup` keeps the payloads of the packets it has
+//! received but not yet written. Small files are fine in memory, but a
+//! multi-gigabyte image is better kept on disk until it's reassembled.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use memmap2::MmapMut;
+
+use crate::{output::temp_file_name, packets::MAX_PACKETS};
+
+/// Storage for packet payloads, keyed by packet number.
+pub trait PacketStore: fmt::Debug + Send {
+    /// The number of packets in the store.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains(&self, packet_number: u16) -> bool;
+
+    /// The payload of the given packet, or `None` if we don't have it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store couldn't read the payload back.
+    fn get(&self, packet_number: u16) -> io::Result<Option<Cow<'_, [u8]>>>;
+
+    /// Store the payload of the given packet, replacing any payload we
+    /// already had for it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store couldn't save the payload, in
+    /// which case the store is unchanged.
+    fn insert(&mut self, packet_number: u16, data: &[u8]) -> io::Result<()>;
+
+    fn remove(&mut self, packet_number: u16);
+
+    /// The numbers of the packets in the store, in increasing order.
+    fn packet_numbers(&self) -> Vec<u16>;
+
+    fn highest_packet_number(&self) -> Option<u16> {
+        self.packet_numbers().last().copied()
+    }
+
+    /// Remove every packet and release the space they used.
+    fn clear(&mut self);
+}
+
+impl PartialEq for dyn PacketStore + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        let packet_numbers = self.packet_numbers();
+        packet_numbers == other.packet_numbers()
+            && packet_numbers.into_iter().all(|packet_number| {
+                matches!(
+                    (self.get(packet_number), other.get(packet_number)),
+                    (Ok(data), Ok(other_data)) if data == other_data
+                )
+            })
+    }
+}
+
+impl Eq for dyn PacketStore + '_ {}
+
+impl Default for Box<dyn PacketStore> {
+    fn default() -> Self {
+        Box::new(MemoryStore::default())
+    }
+}
+
+/// Which kind of `PacketStore` a `FileManager` gives each file.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub enum StorageBackend {
+    /// Keep every payload in memory.
+    #[default]
+    Memory,
+    /// Append payloads to a temporary file in this directory.
+    SpillFile(PathBuf),
+    /// Keep payloads in a memory-mapped sparse temporary file in this
+    /// directory. Each payload can be at most `MAX_SLOT_SIZE` bytes.
+    MemoryMapped(PathBuf),
+}
+
+impl StorageBackend {
+    /// A new, empty store of this kind. Stores that use a file don't
+    /// create it until the first packet arrives.
+    #[must_use]
+    pub fn create_store(&self) -> Box<dyn PacketStore> {
+        match self {
+            Self::Memory => Box::new(MemoryStore::default()),
+            Self::SpillFile(dir) => Box::new(SpillFileStore::new(dir)),
+            Self::MemoryMapped(dir) => Box::new(MemoryMappedStore::new(dir)),
+        }
+    }
+}
+
+// `MemoryStore` only compacts its arena once it has at least this many
+// bytes of garbage, so small files never bother.
+const MIN_GARBAGE_TO_COMPACT: usize = 64 * 1024;
+
+// `MemoryStore` never reserves more than this many times the payloads
+// it holds, so a stray packet with a high number can't make it reserve
+// room for a whole file's worth of packets that may never arrive.
+const MAX_ARENA_GROWTH: usize = 4;
+
+// `MemoryStore` keeps track of the packets in chunks of this many
+// consecutive packet numbers.
+const CHUNK_SIZE: usize = 64;
+
+/// Keeps every payload in memory in one contiguous arena.
+///
+/// Packet numbers are split into chunks of 64, and only the chunks we
+/// have packets in have slots saying where each payload is, so a stray
+/// packet with a high number costs one chunk rather than slots for the
+/// whole file.
+///
+/// Replaced and removed payloads leave garbage in the arena; once at
+/// least half of the arena is garbage the live payloads are copied into
+/// a new one, so the arena is never much more than twice the size of
+/// the payloads.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    arena: Vec<u8>,
+    // Chunk `n / CHUNK_SIZE` holds packet `n`, if we have it. There are
+    // at most `MAX_PACKETS / CHUNK_SIZE` chunks, and the last one is
+    // never `None`.
+    chunks: Vec<Option<Box<Chunk>>>,
+    len: usize,
+    garbage: usize,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    // Bit `n % CHUNK_SIZE` is set if we have packet `n`.
+    received: u64,
+    // Where each packet's payload is in the arena. Only meaningful for
+    // the packets whose bit is set in `received`.
+    slots: [Slot; CHUNK_SIZE],
+}
+
+impl Chunk {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            received: 0,
+            slots: [Slot::default(); CHUNK_SIZE],
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Slot {
+    start: usize,
+    len: usize,
+}
+
+impl Slot {
+    const fn range(self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+}
+
+// The chunk that holds `packet_number`, its slot in that chunk, and the
+// mask for its bit in `received`.
+const fn position(packet_number: u16) -> (usize, usize, u64) {
+    let index = packet_number as usize % CHUNK_SIZE;
+    (packet_number as usize / CHUNK_SIZE, index, 1 << index)
+}
+
+impl MemoryStore {
+    fn slot(&self, packet_number: u16) -> Option<Slot> {
+        let (chunk, index, mask) = position(packet_number);
+        let chunk = self.chunks.get(chunk)?.as_deref()?;
+        (chunk.received & mask != 0).then_some(chunk.slots[index])
+    }
+
+    // Make room for `additional` more bytes in the arena, once the packet
+    // they belong to is counted. Rather than letting the arena double, we
+    // guess how big the file is from the highest packet number we have
+    // and the average payload so far; packets arrive in a fairly random
+    // order, so this quickly gets close and we avoid both copying the
+    // arena and leaving half of it unused. The guess is capped (see
+    // `MAX_ARENA_GROWTH`), so until enough packets have arrived the arena
+    // grows geometrically.
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.arena.len() + additional;
+        if needed <= self.arena.capacity() {
+            return;
+        }
+        let live_bytes = self.arena.len() - self.garbage + additional;
+        let span = self
+            .highest_packet_number()
+            .map_or(0, |packet_number| usize::from(packet_number) + 1);
+        let estimate =
+            (live_bytes / self.len.max(1) * span).min(live_bytes * MAX_ARENA_GROWTH) + self.garbage;
+        self.arena.reserve_exact(estimate.max(needed) - self.arena.len());
+    }
+
+    fn compact_if_needed(&mut self) {
+        if self.garbage < MIN_GARBAGE_TO_COMPACT || self.garbage < self.arena.len() / 2 {
+            return;
+        }
+        let mut arena = Vec::with_capacity(self.arena.len() - self.garbage);
+        for chunk in self.chunks.iter_mut().flatten() {
+            for index in set_bits(chunk.received) {
+                let slot = &mut chunk.slots[index];
+                let start = arena.len();
+                arena.extend_from_slice(&self.arena[slot.range()]);
+                slot.start = start;
+            }
+        }
+        self.arena = arena;
+        self.garbage = 0;
+    }
+}
+
+// The indexes of the bits that are set in `bits`, in increasing order.
+fn set_bits(mut bits: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if bits == 0 {
+            return None;
+        }
+        let index = bits.trailing_zeros() as usize;
+        bits &= bits - 1;
+        Some(index)
+    })
+}
+
+impl PacketStore for MemoryStore {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn contains(&self, packet_number: u16) -> bool {
+        self.slot(packet_number).is_some()
+    }
+
+    fn get(&self, packet_number: u16) -> io::Result<Option<Cow<'_, [u8]>>> {
+        Ok(self
+            .slot(packet_number)
+            .map(|slot| Cow::Borrowed(&self.arena[slot.range()])))
+    }
+
+    fn insert(&mut self, packet_number: u16, data: &[u8]) -> io::Result<()> {
+        let (chunk, index, mask) = position(packet_number);
+        if self.chunks.len() <= chunk {
+            self.chunks.resize_with(chunk + 1, || None);
+        }
+        let chunk = self.chunks[chunk].get_or_insert_with(Chunk::new);
+        if chunk.received & mask == 0 {
+            chunk.received |= mask;
+            self.len += 1;
+        } else {
+            self.garbage += chunk.slots[index].len;
+        }
+        chunk.slots[index] = Slot {
+            start: self.arena.len(),
+            len: data.len(),
+        };
+        self.reserve(data.len());
+        self.arena.extend_from_slice(data);
+        self.compact_if_needed();
+        Ok(())
+    }
+
+    fn remove(&mut self, packet_number: u16) {
+        let Some(slot) = self.slot(packet_number) else {
+            return;
+        };
+        self.len -= 1;
+        if self.len == 0 {
+            self.clear();
+            return;
+        }
+        let (chunk, _, mask) = position(packet_number);
+        let entry = &mut self.chunks[chunk];
+        if let Some(chunk) = entry {
+            chunk.received &= !mask;
+            if chunk.received == 0 {
+                *entry = None;
+            }
+        }
+        while self.chunks.last().is_some_and(Option::is_none) {
+            self.chunks.pop();
+        }
+        self.garbage += slot.len;
+        self.compact_if_needed();
+    }
+
+    fn packet_numbers(&self) -> Vec<u16> {
+        let mut packet_numbers = Vec::with_capacity(self.len);
+        // There are at most `MAX_PACKETS / CHUNK_SIZE` chunks, so every
+        // packet's number fits in a `u16`.
+        for (first_packet_number, chunk) in (0..=u16::MAX).step_by(CHUNK_SIZE).zip(&self.chunks) {
+            let Some(chunk) = chunk else {
+                continue;
+            };
+            for index in set_bits(chunk.received) {
+                // `index` is less than `CHUNK_SIZE` here.
+                #[expect(clippy::cast_possible_truncation)]
+                packet_numbers.push(first_packet_number + index as u16);
+            }
+        }
+        packet_numbers
+    }
+
+    fn highest_packet_number(&self) -> Option<u16> {
+        let last_chunk = self.chunks.last()?.as_deref()?;
+        let index = set_bits(last_chunk.received).last()?;
+        // There are at most `MAX_PACKETS / CHUNK_SIZE` chunks.
+        #[expect(clippy::cast_possible_truncation)]
+        Some(((self.chunks.len() - 1) * CHUNK_SIZE + index) as u16)
+    }
+
+    fn clear(&mut self) {
+        // Replace everything rather than clearing it to free the memory.
+        *self = Self::default();
+    }
+}
+
+// A temporary file that's removed when it's dropped.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(temp_file_name());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Self { path, file })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // There's nothing useful we can do if this fails.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Appends each payload to a temporary file and only keeps its offset
+/// and length in memory. Space used by replaced or removed payloads is
+/// only reclaimed once the store is empty.
+#[derive(Debug)]
+pub struct SpillFileStore {
+    dir: PathBuf,
+    file: Option<TempFile>,
+    // The offset and length of each payload in the file.
+    index: HashMap<u16, (u64, usize)>,
+    end: u64,
+}
+
+impl SpillFileStore {
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            file: None,
+            index: HashMap::new(),
+            end: 0,
+        }
+    }
+}
+
+impl PacketStore for SpillFileStore {
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn contains(&self, packet_number: u16) -> bool {
+        self.index.contains_key(&packet_number)
+    }
+
+    fn get(&self, packet_number: u16) -> io::Result<Option<Cow<'_, [u8]>>> {
+        let (Some(&(offset, len)), Some(temp_file)) = (self.index.get(&packet_number), &self.file)
+        else {
+            return Ok(None);
+        };
+        let mut file = &temp_file.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; len];
+        file.read_exact(&mut data)?;
+        Ok(Some(Cow::Owned(data)))
+    }
+
+    fn insert(&mut self, packet_number: u16, data: &[u8]) -> io::Result<()> {
+        let temp_file = match &mut self.file {
+            Some(temp_file) => temp_file,
+            file @ None => file.insert(TempFile::create(&self.dir)?),
+        };
+        temp_file.file.seek(SeekFrom::Start(self.end))?;
+        temp_file.file.write_all(data)?;
+        self.index.insert(packet_number, (self.end, data.len()));
+        self.end += data.len() as u64;
+        Ok(())
+    }
+
+    fn remove(&mut self, packet_number: u16) {
+        self.index.remove(&packet_number);
+        if self.index.is_empty() {
+            self.clear();
+        }
+    }
+
+    fn packet_numbers(&self) -> Vec<u16> {
+        let mut packet_numbers: Vec<u16> = self.index.keys().copied().collect();
+        packet_numbers.sort_unstable();
+        packet_numbers
+    }
+
+    fn clear(&mut self) {
+        self.index = HashMap::new();
+        // New payloads overwrite the old ones from the start of the file.
+        self.end = 0;
+        if let Some(temp_file) = &self.file {
+            // This only gives the space back, so it doesn't matter if it
+            // fails.
+            let _ = temp_file.file.set_len(0);
+        }
+    }
+}
+
+/// The largest payload a `MemoryMappedStore` can hold. A packet is at
+/// most 1028 bytes, four of which are the data packet's header.
+pub const MAX_SLOT_SIZE: usize = 1024;
+
+// The most a `MemoryMappedStore`'s file grows to: a slot for every
+// packet.
+const MAX_MAPPED_LEN: usize = MAX_PACKETS * MAX_SLOT_SIZE;
+
+#[derive(Debug)]
+struct MappedFile {
+    // Declared before the file so that the mapping goes away first.
+    map: MmapMut,
+    file: TempFile,
+}
+
+/// Keeps each payload in a fixed-size slot of a memory-mapped sparse
+/// file, so the operating system decides how much of it stays in
+/// memory.
+///
+/// The file starts out just big enough for the first packet and at
+/// least doubles whenever a packet doesn't fit, up to a slot for every
+/// packet. Only the slots that are written take up space on file
+/// systems that support sparse files.
+#[derive(Debug)]
+pub struct MemoryMappedStore {
+    dir: PathBuf,
+    file: Option<MappedFile>,
+    lengths: HashMap<u16, usize>,
+}
+
+impl MemoryMappedStore {
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            file: None,
+            lengths: HashMap::new(),
+        }
+    }
+
+    // Make `file` `len` bytes long and map all of it. Growing the file
+    // doesn't disturb an existing mapping of it.
+    fn map_file(file: &TempFile, len: usize) -> io::Result<MmapMut> {
+        file.file.set_len(len as u64)?;
+        // SAFETY: The file is a temporary file with a name no one else
+        // knows, so nothing else should change its length or contents
+        // while it's mapped.
+        #[expect(unsafe_code, reason = "memory mapping a file is inherently unsafe")]
+        unsafe {
+            MmapMut::map_mut(&file.file)
+        }
+    }
+}
+
+impl PacketStore for MemoryMappedStore {
+    fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    fn contains(&self, packet_number: u16) -> bool {
+        self.lengths.contains_key(&packet_number)
+    }
+
+    fn get(&self, packet_number: u16) -> io::Result<Option<Cow<'_, [u8]>>> {
+        let (Some(&len), Some(mapped_file)) = (self.lengths.get(&packet_number), &self.file) else {
+            return Ok(None);
+        };
+        let start = usize::from(packet_number) * MAX_SLOT_SIZE;
+        Ok(Some(Cow::Borrowed(&mapped_file.map[start..start + len])))
+    }
+
+    fn insert(&mut self, packet_number: u16, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_SLOT_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a payload of {} bytes doesn't fit in a {MAX_SLOT_SIZE} byte slot",
+                    data.len()
+                ),
+            ));
+        }
+        let start = usize::from(packet_number) * MAX_SLOT_SIZE;
+        let needed = start + MAX_SLOT_SIZE;
+        let mapped_file = match &mut self.file {
+            Some(mapped_file) => mapped_file,
+            mapped_file @ None => {
+                let file = TempFile::create(&self.dir)?;
+                let map = Self::map_file(&file, needed)?;
+                mapped_file.insert(MappedFile { map, file })
+            }
+        };
+        if mapped_file.map.len() < needed {
+            let len = needed.max(2 * mapped_file.map.len()).min(MAX_MAPPED_LEN);
+            mapped_file.map = Self::map_file(&mapped_file.file, len)?;
+        }
+        mapped_file.map[start..start + data.len()].copy_from_slice(data);
+        self.lengths.insert(packet_number, data.len());
+        Ok(())
+    }
+
+    fn remove(&mut self, packet_number: u16) {
+        self.lengths.remove(&packet_number);
+        if self.lengths.is_empty() {
+            self.clear();
+        }
+    }
+
+    fn packet_numbers(&self) -> Vec<u16> {
+        let mut packet_numbers: Vec<u16> = self.lengths.keys().copied().collect();
+        packet_numbers.sort_unstable();
+        packet_numbers
+    }
+
+    fn clear(&mut self) {
+        self.lengths = HashMap::new();
+        // Dropping the file unmaps and removes it.
+        self.file = None;
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod packet_store_tests {
+    use std::{collections::HashMap, fs, path::Path};
+
+    use super::{
+        MemoryStore, PacketStore, StorageBackend, MAX_ARENA_GROWTH, MAX_SLOT_SIZE,
+        MIN_GARBAGE_TO_COMPACT,
+    };
+
+    fn stores(dir: &Path) -> Vec<Box<dyn PacketStore>> {
+        [
+            StorageBackend::Memory,
+            StorageBackend::SpillFile(dir.join("spill")),
+            StorageBackend::MemoryMapped(dir.join("mmap")),
+        ]
+        .iter()
+        .map(StorageBackend::create_store)
+        .collect()
+    }
+
+    fn files_in(dir: &Path) -> usize {
+        fs::read_dir(dir).map_or(0, Iterator::count)
+    }
+
+    #[test]
+    fn insert_get_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        for mut store in stores(dir.path()) {
+            assert!(store.is_empty());
+            store.insert(5, b"five").unwrap();
+            store.insert(1, b"one").unwrap();
+            store.insert(9, &[]).unwrap();
+            assert_eq!(store.len(), 3);
+            assert_eq!(store.packet_numbers(), vec![1, 5, 9]);
+            assert_eq!(&*store.get(5).unwrap().unwrap(), b"five");
+            assert_eq!(&*store.get(9).unwrap().unwrap(), b"");
+            assert!(store.get(2).unwrap().is_none());
+
+            store.insert(5, b"FIVE!").unwrap();
+            assert_eq!(&*store.get(5).unwrap().unwrap(), b"FIVE!");
+            assert_eq!(&*store.get(1).unwrap().unwrap(), b"one");
+
+            store.remove(1);
+            assert!(!store.contains(1));
+            assert!(store.contains(5));
+            assert_eq!(store.len(), 2);
+
+            store.clear();
+            assert!(store.is_empty());
+            assert!(store.get(5).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn temporary_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        for mut store in stores(dir.path()).into_iter().skip(1) {
+            store.insert(0, b"data").unwrap();
+            store.remove(0);
+            store.insert(1, b"more data").unwrap();
+            drop(store);
+        }
+        for mut store in stores(dir.path()).into_iter().skip(1) {
+            store.insert(0, b"data").unwrap();
+            store.clear();
+            store.insert(0, b"data").unwrap();
+            store.remove(0);
+        }
+        assert_eq!(files_in(&dir.path().join("spill")), 0);
+        assert_eq!(files_in(&dir.path().join("mmap")), 0);
+    }
+
+    #[test]
+    fn stores_only_create_files_when_needed() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = stores(dir.path());
+        assert_eq!(files_in(dir.path()), 0);
+        drop(stores);
+    }
+
+    #[test]
+    fn oversized_payloads_dont_fit_in_a_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = StorageBackend::MemoryMapped(dir.path().to_path_buf()).create_store();
+        store.insert(u16::MAX, &[1; MAX_SLOT_SIZE]).unwrap();
+        assert!(store.insert(3, &[0; MAX_SLOT_SIZE + 1]).is_err());
+        assert!(!store.contains(3));
+        assert_eq!(store.get(u16::MAX).unwrap().unwrap().len(), MAX_SLOT_SIZE);
+    }
+
+    #[test]
+    fn mapped_files_grow_as_packets_arrive() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = StorageBackend::MemoryMapped(dir.path().to_path_buf()).create_store();
+        let file_len = || {
+            let entry = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap();
+            usize::try_from(entry.metadata().unwrap().len()).unwrap()
+        };
+        store.insert(0, b"zero").unwrap();
+        assert_eq!(file_len(), MAX_SLOT_SIZE);
+        store.insert(1, b"one").unwrap();
+        assert_eq!(file_len(), 2 * MAX_SLOT_SIZE);
+        store.insert(10, b"ten").unwrap();
+        assert_eq!(file_len(), 11 * MAX_SLOT_SIZE);
+        assert_eq!(&*store.get(0).unwrap().unwrap(), b"zero");
+        assert_eq!(&*store.get(1).unwrap().unwrap(), b"one");
+        assert_eq!(&*store.get(10).unwrap().unwrap(), b"ten");
+    }
+
+    #[test]
+    fn spill_files_are_reused_after_clearing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = StorageBackend::SpillFile(dir.path().to_path_buf()).create_store();
+        store.insert(0, b"some data").unwrap();
+        store.clear();
+        store.insert(1, b"more").unwrap();
+        assert_eq!(store.packet_numbers(), vec![1]);
+        assert_eq!(&*store.get(1).unwrap().unwrap(), b"more");
+    }
+
+    #[test]
+    fn stores_with_the_same_packets_are_equal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stores = stores(dir.path());
+        for store in &mut stores {
+            store.insert(2, b"two").unwrap();
+        }
+        assert!(stores.windows(2).all(|pair| *pair[0] == *pair[1]));
+        stores[2].insert(2, b"TWO").unwrap();
+        assert!(*stores[0] != *stores[2]);
+    }
+
+    // Every store behaves like a `HashMap`. `None` removes the packet.
+    #[quickcheck_macros::quickcheck]
+    fn stores_behave_like_a_map(operations: Vec<(u16, Option<Vec<u8>>)>) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stores = stores(dir.path());
+        let mut model = HashMap::new();
+        for (packet_number, data) in operations {
+            let data = data.map(|mut data| {
+                data.truncate(MAX_SLOT_SIZE);
+                data
+            });
+            for store in &mut stores {
+                match &data {
+                    Some(data) => store.insert(packet_number, data).unwrap(),
+                    None => store.remove(packet_number),
+                }
+            }
+            match data {
+                Some(data) => model.insert(packet_number, data),
+                None => model.remove(&packet_number),
+            };
+        }
+        let mut packet_numbers: Vec<u16> = model.keys().copied().collect();
+        packet_numbers.sort_unstable();
+        stores.iter().all(|store| {
+            store.len() == model.len()
+                && store.packet_numbers() == packet_numbers
+                && store.highest_packet_number() == packet_numbers.last().copied()
+                && model.iter().all(|(&packet_number, data)| {
+                    store.get(packet_number).unwrap().as_deref() == Some(data.as_slice())
+                })
+        })
+    }
+
+    #[test]
+    fn highest_packet_number() {
+        let mut store = MemoryStore::default();
+        assert_eq!(store.highest_packet_number(), None);
+        for packet_number in [0, 63, 64, 700, u16::MAX] {
+            store.insert(packet_number, &[]).unwrap();
+            assert_eq!(store.highest_packet_number(), Some(packet_number));
+        }
+        store.remove(u16::MAX);
+        assert_eq!(store.highest_packet_number(), Some(700));
+    }
+
+    #[test]
+    fn stray_packets_dont_reserve_a_whole_file() {
+        let mut store = MemoryStore::default();
+        store.insert(u16::MAX, &[0; 1024]).unwrap();
+        assert!(store.arena.capacity() <= MAX_ARENA_GROWTH * 1024);
+    }
+
+    #[test]
+    fn stray_packets_only_take_one_chunk() {
+        let mut store = MemoryStore::default();
+        store.insert(u16::MAX, b"stray").unwrap();
+        assert_eq!(store.chunks.iter().flatten().count(), 1);
+        store.insert(0, b"first").unwrap();
+        store.remove(u16::MAX);
+        assert_eq!(store.chunks.len(), 1);
+        assert_eq!(store.packet_numbers(), vec![0]);
+    }
+
+    #[test]
+    fn arena_is_reserved_for_the_packets_in_between() {
+        let mut store = MemoryStore::default();
+        store.insert(0, &[0; 100]).unwrap();
+        store.insert(3, &[3; 100]).unwrap();
+        // Two packets of 100 bytes with numbers up to 3 suggest a file of
+        // four packets.
+        assert!(store.arena.capacity() >= 400);
+    }
+
+    #[test]
+    fn garbage_is_compacted() {
+        let mut store = MemoryStore::default();
+        let payload = |packet_number: u16| vec![packet_number.to_le_bytes()[0]; 1024];
+        // Stream packets through the store the way `write_prefix` does,
+        // keeping a window of 10 packets.
+        for packet_number in 0..1000 {
+            store
+                .insert(packet_number, &payload(packet_number))
+                .unwrap();
+            if let Some(old) = packet_number.checked_sub(10) {
+                store.remove(old);
+            }
+            assert!(store.arena.len() <= 2 * MIN_GARBAGE_TO_COMPACT + 10 * 1024);
+        }
+        assert_eq!(store.len(), 10);
+        for packet_number in 990..1000 {
+            assert_eq!(
+                store.get(packet_number).unwrap().unwrap(),
+                payload(packet_number)
+            );
+        }
+    }
+}
@@ -1,16 +1,127 @@
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::{
-    ffi::OsString,
-    ops::Not,
+    borrow::Cow,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
+    io::{self, Write},
     str::{self, Utf8Error},
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketKind {
+    Header,
+    Data,
+}
+
+impl Display for PacketKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header => write!(f, "header"),
+            Self::Data => write!(f, "data"),
+        }
+    }
+}
+
+/// How strictly we parse the file name in a header packet.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FileNameMode {
+    /// The file name must be valid UTF-8, otherwise we return a
+    /// `FilenameParseError`.
+    #[default]
+    Strict,
+    /// Accept any bytes as a file name. On Unix-like systems the raw
+    /// bytes are preserved as-is in the `OsString`; elsewhere invalid
+    /// UTF-8 sequences are replaced with `U+FFFD`.
+    Lenient,
+}
+
+// Convert raw file name bytes from the wire into an `OsStr`, borrowing
+// when we can.
+#[cfg(unix)]
+fn os_str_from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    Cow::Borrowed(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_str_from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    match String::from_utf8_lossy(bytes) {
+        Cow::Borrowed(name) => Cow::Borrowed(OsStr::new(name)),
+        Cow::Owned(name) => Cow::Owned(name.into()),
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    os_str_from_bytes(&bytes).into_owned()
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PacketParseError {
-    IncompletePacket,
-    FilenameParseError,
+    /// The packet had fewer bytes than the smallest legal packet of
+    /// its kind.
+    IncompletePacket { actual: usize, minimum: usize },
+    /// The file name in a header packet wasn't legal UTF-8; `offset`
+    /// is the index (within the file name) of the first bad byte.
+    FilenameParseError { offset: usize },
+    /// The status byte marked this as the wrong kind of packet.
+    WrongPacketKind {
+        expected: PacketKind,
+        status_byte: u8,
+    },
 }
 
-#[derive(Debug)]
+impl Display for PacketParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncompletePacket { actual, minimum } => write!(
+                f,
+                "incomplete packet: got {actual} bytes but needed at least {minimum}"
+            ),
+            Self::FilenameParseError { offset } => write!(
+                f,
+                "file name is not valid UTF-8: invalid byte at offset {offset} of the file name"
+            ),
+            Self::WrongPacketKind {
+                expected,
+                status_byte,
+            } => write!(
+                f,
+                "expected a {expected} packet but got status byte {status_byte}"
+            ),
+        }
+    }
+}
+
+impl Error for PacketParseError {}
+
+/// Why `Header::new` or `Data::new` couldn't build a packet. These are
+/// packets that couldn't be sent over the wire, because every field
+/// after the header bytes must have at least one byte.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketError {
+    EmptyFileName,
+    EmptyData,
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyFileName => write!(f, "the file name is empty"),
+            Self::EmptyData => write!(f, "the data is empty"),
+        }
+    }
+}
+
+impl Error for PacketError {}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Packet {
     Header(Header),
     Data(Data),
@@ -21,9 +132,23 @@ impl Packet {
     //  bytes.is_empty().not().then(|| bytes[0] % 2 == 0).ok_or(PacketParseError::IncompletePacket)
     const fn is_header(bytes: &[u8]) -> Result<bool, PacketParseError> {
         if bytes.is_empty() {
-            return Err(PacketParseError::IncompletePacket);
+            return Err(PacketParseError::IncompletePacket {
+                actual: 0,
+                minimum: 1,
+            });
         }
-        Ok(bytes[0] % 2 == 0)
+        Ok(bytes[0].is_multiple_of(2))
+    }
+
+    /// Parse the given bytes as a packet, using `mode` to decide how
+    /// to handle file names that aren't valid UTF-8.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bytes aren't a legal packet; see
+    /// `HeaderRef::parse` and `DataRef::try_from`.
+    pub fn parse(bytes: &[u8], mode: FileNameMode) -> Result<Self, PacketParseError> {
+        PacketRef::parse(bytes, mode).map(PacketRef::to_owned)
     }
 
     #[must_use]
@@ -33,17 +158,50 @@ impl Packet {
             Self::Data(data) => data.file_id,
         }
     }
+
+    /// Convert this packet into the bytes that would be sent over
+    /// the wire, i.e., the inverse of `Packet::try_from(&[u8])`.
+    /// Headers always have a file name and data packets always have
+    /// data (`Header::new` and `Data::new` insist on it), so the
+    /// result always parses again.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Header(header) => header.encode(),
+            Self::Data(data) => data.encode(),
+        }
+    }
+
+    /// Write the wire encoding of this packet to the given writer.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to `writer` fails.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Header(header) => header.write_to(writer),
+            Self::Data(data) => data.write_to(writer),
+        }
+    }
+}
+
+impl From<Header> for Packet {
+    fn from(header: Header) -> Self {
+        Self::Header(header)
+    }
+}
+
+impl From<Data> for Packet {
+    fn from(data: Data) -> Self {
+        Self::Data(data)
+    }
 }
 
 impl TryFrom<&[u8]> for Packet {
     type Error = PacketParseError;
 
     fn try_from(bytes: &[u8]) -> Result<Self, PacketParseError> {
-        if Self::is_header(bytes)? {
-            Ok(Self::Header(bytes.try_into()?))
-        } else {
-            Ok(Self::Data(bytes.try_into()?))
-        }
+        Self::parse(bytes, FileNameMode::Strict)
     }
 }
 
@@ -54,71 +212,417 @@ pub struct Header {
 }
 
 impl From<Utf8Error> for PacketParseError {
-    fn from(_: Utf8Error) -> Self {
-        Self::FilenameParseError
+    fn from(error: Utf8Error) -> Self {
+        Self::FilenameParseError {
+            offset: error.valid_up_to(),
+        }
     }
 }
 
-// TODO: Look at how we could use the zerocopy crate to automagic
-//   some of this conversion.
 impl TryFrom<&[u8]> for Header {
     type Error = PacketParseError;
 
-    /// Convert the given byte array slice to a header packet.
-    /// This assumes
+    /// Convert the given byte array slice to a header packet, requiring
+    /// the file name to be valid UTF-8. See `Header::parse`.
+    fn try_from(bytes: &[u8]) -> Result<Self, PacketParseError> {
+        Self::parse(bytes, FileNameMode::Strict)
+    }
+}
+
+impl Header {
+    // Any even status byte marks a header packet; we always send 0.
+    const STATUS_BYTE: u8 = 0;
+
+    /// A header packet announcing the file with this ID and name.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `file_name` is empty.
+    pub fn new(file_id: u8, file_name: impl Into<OsString>) -> Result<Self, PacketError> {
+        let file_name = file_name.into();
+        if file_name.is_empty() {
+            return Err(PacketError::EmptyFileName);
+        }
+        Ok(Self { file_id, file_name })
+    }
+
+    #[must_use]
+    pub const fn file_id(&self) -> u8 {
+        self.file_id
+    }
+
+    #[must_use]
+    pub fn file_name(&self) -> &OsStr {
+        &self.file_name
+    }
+
+    /// Convert the given byte array slice to a header packet. This
+    /// parses the bytes as a `HeaderRef` (see there for the
+    /// assumptions we make) and then copies the file name.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bytes aren't a legal header packet.
+    pub fn parse(bytes: &[u8], mode: FileNameMode) -> Result<Self, PacketParseError> {
+        HeaderRef::parse(bytes, mode).map(HeaderRef::to_owned)
+    }
+
+    /// Convert this header into the bytes that would be sent over
+    /// the wire: the status byte, the file ID, and then the bytes
+    /// of the file name.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let file_name = self.file_name.as_encoded_bytes();
+        let mut bytes = Vec::with_capacity(2 + file_name.len());
+        bytes.push(Self::STATUS_BYTE);
+        bytes.push(self.file_id);
+        bytes.extend_from_slice(file_name);
+        bytes
+    }
+
+    /// Write the wire encoding of this header to the given writer.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to `writer` fails.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[Self::STATUS_BYTE, self.file_id])?;
+        writer.write_all(self.file_name.as_encoded_bytes())
+    }
+}
+
+/// The most packets a file can have, since packet numbers are `u16`s.
+pub const MAX_PACKETS: usize = u16::MAX as usize + 1;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[expect(
+    clippy::struct_field_names,
+    reason = "`data` is the name the lab write-up uses for a packet's payload"
+)]
+pub struct Data {
+    pub(crate) file_id: u8,
+    pub(crate) packet_number: u16,
+    pub(crate) is_last_packet: bool,
+    pub(crate) data: Vec<u8>,
+}
+
+impl TryFrom<&[u8]> for Data {
+    type Error = PacketParseError;
+
+    /// Convert the given byte array slice to a data packet. This
+    /// parses the bytes as a `DataRef` (see there for the
+    /// assumptions we make) and then copies the data.
+    fn try_from(bytes: &[u8]) -> Result<Self, PacketParseError> {
+        DataRef::try_from(bytes).map(DataRef::to_owned)
+    }
+}
+
+impl Data {
+    // Odd status bytes mark data packets; the second bit marks the
+    // last packet in a file.
+    const STATUS_BYTE: u8 = 1;
+    const LAST_PACKET_STATUS_BYTE: u8 = 3;
+
+    /// A data packet that isn't the last one in its file; see
+    /// `with_last_packet`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `data` is empty.
+    pub fn new(
+        file_id: u8,
+        packet_number: u16,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<Self, PacketError> {
+        let data = data.into();
+        if data.is_empty() {
+            return Err(PacketError::EmptyData);
+        }
+        Ok(Self {
+            file_id,
+            packet_number,
+            is_last_packet: false,
+            data,
+        })
+    }
+
+    /// Mark this as the last packet in its file (or not).
+    #[must_use]
+    pub const fn with_last_packet(mut self, is_last_packet: bool) -> Self {
+        self.is_last_packet = is_last_packet;
+        self
+    }
+
+    #[must_use]
+    pub const fn file_id(&self) -> u8 {
+        self.file_id
+    }
+
+    #[must_use]
+    pub const fn packet_number(&self) -> u16 {
+        self.packet_number
+    }
+
+    #[must_use]
+    pub const fn is_last_packet(&self) -> bool {
+        self.is_last_packet
+    }
+
+    #[must_use]
+    pub const fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
+    /// Take the data out of the packet without copying it.
+    #[must_use]
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    const fn status_byte(&self) -> u8 {
+        if self.is_last_packet {
+            Self::LAST_PACKET_STATUS_BYTE
+        } else {
+            Self::STATUS_BYTE
+        }
+    }
+
+    /// Convert this data packet into the bytes that would be sent
+    /// over the wire: the status byte, the file ID, the packet number
+    /// (big-endian), and then the data bytes.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.data.len());
+        bytes.push(self.status_byte());
+        bytes.push(self.file_id);
+        bytes.extend_from_slice(&self.packet_number.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Write the wire encoding of this data packet to the given writer.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to `writer` fails.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[self.status_byte(), self.file_id])?;
+        writer.write_all(&self.packet_number.to_be_bytes())?;
+        writer.write_all(&self.data)
+    }
+}
+
+/// A borrowed view of a packet that is parsed directly over a
+/// receive buffer without allocating. Use `to_owned()` to copy it
+/// into a `Packet` when it needs to outlive the buffer.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PacketRef<'a> {
+    Header(HeaderRef<'a>),
+    Data(DataRef<'a>),
+}
+
+impl PacketRef<'_> {
+    #[must_use]
+    pub const fn file_id(self) -> u8 {
+        match self {
+            Self::Header(header) => header.file_id,
+            Self::Data(data) => data.file_id,
+        }
+    }
+
+    #[must_use]
+    pub fn to_owned(self) -> Packet {
+        match self {
+            Self::Header(header) => Packet::Header(header.to_owned()),
+            Self::Data(data) => Packet::Data(data.to_owned()),
+        }
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parse the given bytes as a packet, using `mode` to decide how
+    /// to handle file names that aren't valid UTF-8.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the bytes aren't a legal packet; see
+    /// `HeaderRef::parse` and `DataRef::try_from`.
+    pub fn parse(bytes: &'a [u8], mode: FileNameMode) -> Result<Self, PacketParseError> {
+        if Packet::is_header(bytes)? {
+            Ok(Self::Header(HeaderRef::parse(bytes, mode)?))
+        } else {
+            Ok(Self::Data(bytes.try_into()?))
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for PacketRef<'a> {
+    type Error = PacketParseError;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, PacketParseError> {
+        Self::parse(bytes, FileNameMode::Strict)
+    }
+}
+
+/// A borrowed view of a header packet; see `PacketRef`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct HeaderRef<'a> {
+    pub(crate) file_id: u8,
+    // The raw file name bytes from the packet; these are only
+    // guaranteed to be valid UTF-8 if we parsed in strict mode.
+    pub(crate) file_name: &'a [u8],
+}
+
+impl<'a> HeaderRef<'a> {
+    // The status byte, the file ID, and at least one byte of file name.
+    const MINIMUM_LENGTH: usize = 3;
+
+    #[must_use]
+    pub const fn file_id(self) -> u8 {
+        self.file_id
+    }
+
+    /// The raw bytes of the file name, exactly as they were sent.
+    #[must_use]
+    pub const fn file_name_bytes(self) -> &'a [u8] {
+        self.file_name
+    }
+
+    /// The file name as an `OsStr`. This only allocates if we parsed
+    /// a non-UTF-8 name in lenient mode on a non-Unix platform.
+    #[must_use]
+    pub fn file_name(self) -> Cow<'a, OsStr> {
+        os_str_from_bytes(self.file_name)
+    }
+
+    #[must_use]
+    pub fn to_owned(self) -> Header {
+        Header {
+            file_id: self.file_id,
+            file_name: os_string_from_bytes(self.file_name.to_vec()),
+        }
+    }
+
+    // TODO: Look at how we could use the zerocopy crate to automagic
+    //   some of this conversion.
+    /// Parse the given byte array slice as a header packet, borrowing
+    /// the file name from `bytes`. This assumes
     ///   * All the bytes in the given slice are
     ///     used (i.e., there are no "empty" or unused bytes at the
     ///     end)
     ///   * There are at least 3 bytes (the minimal size for a header packet)
-    ///   * This is actually a header packet (i.e., the first byte is even)
-    ///   * Bytes 2.. can be parsed as a String
-    fn try_from(bytes: &[u8]) -> Result<Self, PacketParseError> {
-        if bytes.len() < 3 {
-            return Err(PacketParseError::IncompletePacket);
+    ///   * This is actually a header packet (i.e., the first byte is even);
+    ///     if it isn't we return a `WrongPacketKind` error
+    ///   * In `FileNameMode::Strict`, bytes 2.. can be parsed as a String
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of those assumptions don't hold.
+    pub fn parse(bytes: &'a [u8], mode: FileNameMode) -> Result<Self, PacketParseError> {
+        if bytes.len() < HeaderRef::MINIMUM_LENGTH {
+            return Err(PacketParseError::IncompletePacket {
+                actual: bytes.len(),
+                minimum: HeaderRef::MINIMUM_LENGTH,
+            });
+        }
+        if !Packet::is_header(bytes)? {
+            return Err(PacketParseError::WrongPacketKind {
+                expected: PacketKind::Header,
+                status_byte: bytes[0],
+            });
         }
-        assert!(
-            Packet::is_header(bytes)?,
-            "expected a header packet but first byte was not even"
-        );
         let file_id = bytes[1];
-        // The `.into()` converts a Rust string into an `OsString`.
-        let file_name = str::from_utf8(&bytes[2..])?.to_string().into();
+        let file_name = &bytes[2..];
+        if mode == FileNameMode::Strict {
+            str::from_utf8(file_name)?;
+        }
 
         Ok(Self { file_id, file_name })
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Data {
+impl<'a> TryFrom<&'a [u8]> for HeaderRef<'a> {
+    type Error = PacketParseError;
+
+    fn try_from(bytes: &'a [u8]) -> Result<Self, PacketParseError> {
+        Self::parse(bytes, FileNameMode::Strict)
+    }
+}
+
+/// A borrowed view of a data packet; see `PacketRef`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DataRef<'a> {
     pub(crate) file_id: u8,
     pub(crate) packet_number: u16,
     pub(crate) is_last_packet: bool,
-    pub(crate) data: Vec<u8>,
+    pub(crate) data: &'a [u8],
 }
 
-impl TryFrom<&[u8]> for Data {
+impl<'a> DataRef<'a> {
+    // The status byte, the file ID, two bytes of packet number, and at
+    // least one byte of data.
+    const MINIMUM_LENGTH: usize = 5;
+
+    #[must_use]
+    pub const fn file_id(self) -> u8 {
+        self.file_id
+    }
+
+    #[must_use]
+    pub const fn packet_number(self) -> u16 {
+        self.packet_number
+    }
+
+    #[must_use]
+    pub const fn is_last_packet(self) -> bool {
+        self.is_last_packet
+    }
+
+    #[must_use]
+    pub const fn data(self) -> &'a [u8] {
+        self.data
+    }
+
+    #[must_use]
+    pub fn to_owned(self) -> Data {
+        Data {
+            file_id: self.file_id,
+            packet_number: self.packet_number,
+            is_last_packet: self.is_last_packet,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for DataRef<'a> {
     type Error = PacketParseError;
 
-    /// Convert the given byte array slice to a header packet.
-    /// This assumes
+    /// Parse the given byte array slice as a data packet, borrowing
+    /// the data from `bytes`. This assumes
     ///   * All the bytes in the given slice are
     ///     used (i.e., there are no "empty" or unused bytes at the
     ///     end)
     ///   * There are at least 5 bytes (the minimal size for a data packet)
-    ///   * This is actually a data packet (i.e., the first byte is odd)
-    fn try_from(bytes: &[u8]) -> Result<Self, PacketParseError> {
-        if bytes.len() < 5 {
-            return Err(PacketParseError::IncompletePacket);
+    ///   * This is actually a data packet (i.e., the first byte is odd);
+    ///     if it isn't we return a `WrongPacketKind` error
+    fn try_from(bytes: &'a [u8]) -> Result<Self, PacketParseError> {
+        if bytes.len() < DataRef::MINIMUM_LENGTH {
+            return Err(PacketParseError::IncompletePacket {
+                actual: bytes.len(),
+                minimum: DataRef::MINIMUM_LENGTH,
+            });
+        }
+        if Packet::is_header(bytes)? {
+            return Err(PacketParseError::WrongPacketKind {
+                expected: PacketKind::Data,
+                status_byte: bytes[0],
+            });
         }
-        assert!(
-            Packet::is_header(bytes)?.not(),
-            "expected a data packet but first byte was not odd"
-        );
         let file_id = bytes[1];
         let packet_number_bytes: [u8; 2] = [bytes[2], bytes[3]];
         let packet_number = u16::from_be_bytes(packet_number_bytes);
         let is_last_packet = bytes[0] % 4 == 3;
-        let data = bytes[4..].to_vec();
+        let data = &bytes[4..];
 
         Ok(Self {
             file_id,
@@ -165,33 +669,57 @@ mod is_header_tests {
     fn error_on_empty_array() {
         let bytes: Vec<u8> = vec![];
         let result = Packet::is_header(&bytes);
-        assert_eq!(result, Err(PacketParseError::IncompletePacket));
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 0,
+                minimum: 1
+            })
+        );
     }
 }
 
 #[cfg(test)]
 mod parse_header_tests {
-    use super::{Header, PacketParseError};
+    use super::{FileNameMode, Header, PacketKind, PacketParseError};
 
     #[test]
     fn error_on_empty_array() {
         let bytes: Vec<u8> = vec![];
         let result = Header::try_from(bytes.as_slice());
-        assert_eq!(result, Err(PacketParseError::IncompletePacket));
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 0,
+                minimum: 3
+            })
+        );
     }
 
     #[test]
     fn error_on_short_array() {
         let bytes: Vec<u8> = vec![0, 1];
         let result = Header::try_from(bytes.as_slice());
-        assert_eq!(result, Err(PacketParseError::IncompletePacket));
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 2,
+                minimum: 3
+            })
+        );
     }
 
     #[test]
-    #[should_panic(expected = "expected a header packet but first byte was not even")]
-    fn non_header_panics() {
+    fn error_on_non_header() {
         let bytes: Vec<u8> = vec![1, 5, 8, 9, 6, 3, 2, 0];
-        let _ = Header::try_from(bytes.as_slice());
+        let result = Header::try_from(bytes.as_slice());
+        assert_eq!(
+            result,
+            Err(PacketParseError::WrongPacketKind {
+                expected: PacketKind::Header,
+                status_byte: 1
+            })
+        );
     }
 
     #[test]
@@ -227,34 +755,103 @@ mod parse_header_tests {
         // the first byte in the emoji sequence with a 0.
         let sparkle_heart: Vec<u8> = vec![0, 0, 0, 159, 146, 150];
         let result = Header::try_from(sparkle_heart.as_slice());
-        assert_eq!(result, Err(PacketParseError::FilenameParseError));
+        assert_eq!(
+            result,
+            Err(PacketParseError::FilenameParseError { offset: 1 })
+        );
+    }
+
+    #[test]
+    fn illegal_file_name_offset() {
+        // The name starts with "ab", so the bad byte is at offset 2
+        // within the file name (and offset 4 within the packet).
+        let bytes: Vec<u8> = vec![0, 0, b'a', b'b', 159, 146, 150];
+        let result = Header::try_from(bytes.as_slice());
+        assert_eq!(
+            result,
+            Err(PacketParseError::FilenameParseError { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn strict_mode_rejects_latin1_file_name() {
+        // "café" encoded as Latin-1, where `é` is the single byte 0xE9.
+        let bytes: Vec<u8> = vec![0, 4, b'c', b'a', b'f', 0xE9];
+        let result = Header::parse(bytes.as_slice(), FileNameMode::Strict);
+        assert_eq!(
+            result,
+            Err(PacketParseError::FilenameParseError { offset: 3 })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lenient_mode_preserves_raw_bytes() {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+        let bytes: Vec<u8> = vec![0, 4, b'c', b'a', b'f', 0xE9];
+        let result = Header::parse(bytes.as_slice(), FileNameMode::Lenient);
+        assert_eq!(
+            result,
+            Ok(Header {
+                file_id: 4,
+                file_name: OsString::from_vec(vec![b'c', b'a', b'f', 0xE9])
+            })
+        );
+    }
+
+    #[test]
+    fn lenient_mode_matches_strict_mode_on_utf8() {
+        let bytes = "\x00\x0CThis file is lovely 💖".as_bytes();
+        assert_eq!(
+            Header::parse(bytes, FileNameMode::Lenient),
+            Header::parse(bytes, FileNameMode::Strict)
+        );
     }
 }
 
 #[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
 #[cfg(test)]
 mod parse_data_tests {
-    use super::{Data, PacketParseError};
+    use super::{Data, PacketKind, PacketParseError};
 
     #[test]
     fn error_on_empty_array() {
         let bytes: Vec<u8> = vec![];
         let result = Data::try_from(bytes.as_slice());
-        assert_eq!(result, Err(PacketParseError::IncompletePacket));
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 0,
+                minimum: 5
+            })
+        );
     }
 
     #[test]
     fn error_on_short_array() {
         let bytes: Vec<u8> = vec![0, 1, 2, 3];
         let result = Data::try_from(bytes.as_slice());
-        assert_eq!(result, Err(PacketParseError::IncompletePacket));
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 4,
+                minimum: 5
+            })
+        );
     }
 
     #[test]
-    #[should_panic(expected = "expected a data packet but first byte was not odd")]
-    fn non_data_panics() {
+    fn error_on_non_data() {
         let bytes: Vec<u8> = vec![0, 5, 8, 9, 6, 3, 2, 0];
-        let _ = Data::try_from(bytes.as_slice());
+        let result = Data::try_from(bytes.as_slice());
+        assert_eq!(
+            result,
+            Err(PacketParseError::WrongPacketKind {
+                expected: PacketKind::Data,
+                status_byte: 0
+            })
+        );
     }
 
     #[test]
@@ -286,24 +883,277 @@ mod parse_data_tests {
     }
 }
 
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[cfg(test)]
+mod constructor_tests {
+    use std::ffi::OsStr;
+
+    use super::{Data, FileNameMode, Header, Packet, PacketError};
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header::new(7, "notes.txt").unwrap();
+        assert_eq!(header.file_id(), 7);
+        assert_eq!(header.file_name(), OsStr::new("notes.txt"));
+        let packet = Packet::from(header.clone());
+        assert_eq!(
+            Packet::parse(&packet.encode(), FileNameMode::Strict),
+            Ok(Packet::Header(header))
+        );
+    }
+
+    #[test]
+    fn data_round_trips() {
+        let data = Data::new(7, 300, vec![1, 2, 3])
+            .unwrap()
+            .with_last_packet(true);
+        assert_eq!(data.file_id(), 7);
+        assert_eq!(data.packet_number(), 300);
+        assert!(data.is_last_packet());
+        assert_eq!(data.data(), [1, 2, 3]);
+        let packet = Packet::from(data.clone());
+        assert_eq!(
+            Packet::parse(&packet.encode(), FileNameMode::Strict),
+            Ok(Packet::Data(data.clone()))
+        );
+        assert_eq!(data.into_data(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn data_packets_are_not_last_by_default() {
+        assert!(!Data::new(0, 0, *b"x").unwrap().is_last_packet());
+    }
+
+    #[test]
+    fn empty_fields_are_rejected() {
+        assert_eq!(Header::new(0, ""), Err(PacketError::EmptyFileName));
+        assert_eq!(Data::new(0, 0, Vec::new()), Err(PacketError::EmptyData));
+        assert_eq!(
+            PacketError::EmptyFileName.to_string(),
+            "the file name is empty"
+        );
+    }
+}
+
+#[cfg(test)]
+mod display_tests {
+    use super::{PacketKind, PacketParseError};
+
+    #[test]
+    fn display_incomplete_packet() {
+        let error = PacketParseError::IncompletePacket {
+            actual: 2,
+            minimum: 3,
+        };
+        assert_eq!(
+            error.to_string(),
+            "incomplete packet: got 2 bytes but needed at least 3"
+        );
+    }
+
+    #[test]
+    fn display_file_name_error() {
+        let error = PacketParseError::FilenameParseError { offset: 7 };
+        assert_eq!(
+            error.to_string(),
+            "file name is not valid UTF-8: invalid byte at offset 7 of the file name"
+        );
+    }
+
+    #[test]
+    fn display_wrong_packet_kind() {
+        let error = PacketParseError::WrongPacketKind {
+            expected: PacketKind::Data,
+            status_byte: 4,
+        };
+        assert_eq!(
+            error.to_string(),
+            "expected a data packet but got status byte 4"
+        );
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[expect(
+    clippy::needless_pass_by_value,
+    reason = "quickcheck properties take their inputs by value"
+)]
+#[cfg(test)]
+mod encode_tests {
+    use super::{Data, FileNameMode, Header, Packet};
+
+    #[test]
+    fn encode_header() {
+        let header = Header {
+            file_id: 12,
+            file_name: "small.txt".to_string().into(),
+        };
+        assert_eq!(header.encode(), b"\x00\x0Csmall.txt");
+    }
+
+    #[test]
+    fn encode_data() {
+        let data = Data {
+            file_id: 5,
+            packet_number: 8 * 256 + 9,
+            is_last_packet: false,
+            data: vec![3, 2, 0],
+        };
+        assert_eq!(data.encode(), vec![1, 5, 8, 9, 3, 2, 0]);
+    }
+
+    #[test]
+    fn encode_last_data_packet() {
+        let data = Data {
+            file_id: 5,
+            packet_number: 8 * 256 + 9,
+            is_last_packet: true,
+            data: vec![3, 2, 0],
+        };
+        assert_eq!(data.encode(), vec![3, 5, 8, 9, 3, 2, 0]);
+    }
+
+    #[test]
+    fn write_to_matches_encode() {
+        let packet = Packet::Data(Data {
+            file_id: 5,
+            packet_number: 17,
+            is_last_packet: true,
+            data: vec![3, 2, 0],
+        });
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes, packet.encode());
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn header_round_trip(header: Header) -> bool {
+        Header::try_from(header.encode().as_slice()) == Ok(header)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn data_round_trip(data: Data) -> bool {
+        Data::try_from(data.encode().as_slice()) == Ok(data)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn packet_round_trip(packet: Packet) -> bool {
+        Packet::try_from(packet.encode().as_slice()) == Ok(packet)
+    }
+
+    // Any file name at all, as long as it isn't empty.
+    #[cfg(unix)]
+    #[quickcheck_macros::quickcheck]
+    fn lenient_header_round_trip(file_id: u8, first_byte: u8, rest: Vec<u8>) -> bool {
+        use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+        let mut file_name = vec![first_byte];
+        file_name.extend(rest);
+        let header = Header {
+            file_id,
+            file_name: OsString::from_vec(file_name),
+        };
+        Header::parse(header.encode().as_slice(), FileNameMode::Lenient) == Ok(header)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn write_to_round_trip(packet: Packet) -> bool {
+        let mut bytes = Vec::new();
+        packet.write_to(&mut bytes).unwrap();
+        bytes == packet.encode()
+    }
+}
+
+#[expect(clippy::unwrap_used, reason = "Unwrap is OK in tests")]
+#[expect(
+    clippy::needless_pass_by_value,
+    reason = "quickcheck properties take their inputs by value"
+)]
+#[cfg(test)]
+mod packet_ref_tests {
+    use std::ffi::OsStr;
+
+    use super::{DataRef, HeaderRef, Packet, PacketParseError, PacketRef};
+
+    #[test]
+    fn header_ref_borrows_file_name() {
+        let bytes = b"\x00\x0Csmall.txt";
+        let header = HeaderRef::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(12, header.file_id());
+        assert_eq!(OsStr::new("small.txt"), header.file_name());
+        assert_eq!(bytes[2..].as_ptr(), header.file_name_bytes().as_ptr());
+    }
+
+    #[test]
+    fn data_ref_borrows_data() {
+        let bytes: Vec<u8> = vec![3, 5, 8, 9, 3, 2, 0];
+        let data = DataRef::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(5, data.file_id());
+        assert_eq!(8 * 256 + 9, data.packet_number());
+        assert!(data.is_last_packet());
+        assert_eq!(bytes[4..].as_ptr(), data.data().as_ptr());
+    }
+
+    #[test]
+    fn error_on_empty_array() {
+        let bytes: Vec<u8> = vec![];
+        let result = PacketRef::try_from(bytes.as_slice());
+        assert_eq!(
+            result,
+            Err(PacketParseError::IncompletePacket {
+                actual: 0,
+                minimum: 1
+            })
+        );
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn to_owned_round_trip(packet: Packet) -> bool {
+        let bytes = packet.encode();
+        let packet_ref = PacketRef::try_from(bytes.as_slice()).unwrap();
+        packet_ref.to_owned() == packet && packet_ref.file_id() == packet.file_id()
+    }
+}
+
 use quickcheck::{Arbitrary, Gen};
 
+impl Arbitrary for Packet {
+    fn arbitrary(g: &mut Gen) -> Self {
+        if bool::arbitrary(g) {
+            Self::Header(Header::arbitrary(g))
+        } else {
+            Self::Data(Data::arbitrary(g))
+        }
+    }
+}
+
+// Like the constructors, these never make an empty file name or empty
+// data, so every packet they make can be encoded and parsed again.
+
 impl Arbitrary for Header {
     fn arbitrary(g: &mut Gen) -> Self {
+        let mut file_name = String::arbitrary(g);
+        if file_name.is_empty() {
+            file_name.push(char::arbitrary(g));
+        }
         Self {
             file_id: u8::arbitrary(g),
-            file_name: String::arbitrary(g).into(),
+            file_name: file_name.into(),
         }
     }
 }
 
 impl Arbitrary for Data {
     fn arbitrary(g: &mut Gen) -> Self {
+        let mut data = Vec::arbitrary(g);
+        if data.is_empty() {
+            data.push(u8::arbitrary(g));
+        }
         Self {
             file_id: u8::arbitrary(g),
             packet_number: u16::arbitrary(g),
             is_last_packet: bool::arbitrary(g),
-            data: Vec::arbitrary(g),
+            data,
         }
     }
 }
@@ -0,0 +1,49 @@
+//! Packets, files and output directories shared by the unit tests.
+
+use tempfile::TempDir;
+
+use crate::{
+    file_manager::FileManager,
+    output::OutputConfig,
+    packet_group::{PacketGroup, PacketOutcome},
+    packets::{Data, Header, Packet},
+};
+
+pub fn header(file_id: u8, file_name: &str) -> Packet {
+    Packet::Header(Header {
+        file_id,
+        file_name: file_name.into(),
+    })
+}
+
+pub fn data(file_id: u8, packet_number: u16, is_last_packet: bool, data: &[u8]) -> Packet {
+    Packet::Data(Data {
+        file_id,
+        packet_number,
+        is_last_packet,
+        data: data.to_vec(),
+    })
+}
+
+// Gives `packet` to `group`, which has to accept it.
+pub fn add(group: &mut PacketGroup, packet: Packet) -> PacketOutcome {
+    group.process_packet(packet).unwrap()
+}
+
+// A whole file: its header, then `contents` in a single last packet.
+pub fn add_file(file_manager: &mut FileManager, file_id: u8, file_name: &str, contents: &[u8]) {
+    file_manager
+        .process_packet(header(file_id, file_name))
+        .unwrap();
+    file_manager
+        .process_packet(data(file_id, 0, true, contents))
+        .unwrap();
+}
+
+// An empty directory, which is removed when the `TempDir` is dropped, and
+// a config that writes files into it.
+pub fn output_dir() -> (TempDir, OutputConfig) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = OutputConfig::new(dir.path());
+    (dir, config)
+}